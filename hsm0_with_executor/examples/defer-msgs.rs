@@ -27,7 +27,10 @@ impl DeferMsgsSm {
     pub fn new() -> Result<Executor<Self, Messages>, DynError> {
         let sm = RefCell::new(DeferMsgsSm { val: 0 });
         let sme = Executor::new(sm, MAX_STATES)
-            .state(StateInfo::new("starting", Self::deferring))
+            .state(
+                StateInfo::new("starting", Self::deferring)
+                    .defer(|msg| matches!(msg, Messages::DeferredValue { .. })),
+            )
             .state(StateInfo::new("deferring", Self::do_deferred_work))
             .build(IDX_DEFERRING)
             .expect("Unexpected error initializing");
@@ -43,14 +46,13 @@ impl DeferMsgsSm {
 
     fn deferring(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
         match msg {
-            Messages::DeferredValue { val } => {
-                log::info!("deferring: Messages::DeferredValue:+ val={}", val);
-                e.defer_send(msg.clone()).unwrap();
-                (Handled::Yes, None)
+            Messages::DeferredValue { .. } => {
+                log::info!("deferring: Messages::DeferredValue not deferred, Unexpected Dropping");
+                (Handled::No, None)
             }
             Messages::Complete { tx: _ } => {
                 log::info!("deferring: Messages::Complete, transition to do_deferred_work");
-                e.defer_send(msg.clone()).unwrap();
+                e.defer_current(msg.clone()).unwrap();
                 (Handled::Yes, Some(IDX_DO_DEFERRED_WORK))
             }
             Messages::Done { val: _ } => {
@@ -95,13 +97,13 @@ fn main() {
     // Dispatch DeferredValue messages
     for _ in 0..10 {
         let msg = Messages::DeferredValue { val: 1 };
-        sme.dispatcher(&msg);
+        sme.dispatcher(&msg).unwrap();
         log::info!("main: Sent {msg:?}");
     }
 
     let (tx, rx) = std::sync::mpsc::channel::<Messages>();
     let msg = Messages::Complete { tx };
-    sme.dispatcher(&msg);
+    sme.dispatcher(&msg).unwrap();
     log::info!("main: Sent {msg:?}");
 
     // We should now recive one Messages::Done
@@ -1,32 +1,33 @@
-/// Currently this is "working" but the biggest know problem
-/// is that when passing buffers around I've got to clone them
-/// when I'd like to achieve zero-copy!
+/// Buffers are passed around without copying, the states that
+/// need to keep part of a message are created with StateInfo::new_mut
+/// and take it with std::mem::take or Option::take.
 use std::{
     cell::RefCell,
     fs::File,
     io::Read,
     sync::mpsc::{channel, Receiver, Sender},
-    thread,
 };
 
 use custom_logger::env_logger_init;
 
-use hsm0_with_executor::{DynError, Executor, Handled, StateInfo, StateResult};
+use hsm0_with_executor::{state_ids, DynError, Executor, Handled, StateInfo, StateResult};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum Messages {
     Open {
         // Name of file open
         file_name: String,
         buf_count: usize,
         buf_capacity: usize,
-        partner_tx: Sender<Messages>,
+        partner_tx: Option<Sender<Messages>>,
     },
+
+    // Also passed to the enter and exit fns when
+    // the executor is started and stopped
+    #[default]
     Start,
     Read,
 
-    // Currently I'm copying data around
-    // but need to implement zero-copy
     Data {
         buf: Vec<u8>,
     },
@@ -35,14 +36,6 @@ pub enum Messages {
         // Return an empty buffer
         buf: Box<Vec<u8>>,
     },
-
-    Done {
-        // true successful, false failed
-        // TODO: Return Error
-        result: bool,
-    },
-
-    StopThread,
 }
 
 #[allow(unused)]
@@ -56,22 +49,29 @@ pub struct FileStreamProducer {
     partner_tx: Option<Sender<Messages>>,
     file: Option<File>,
 
-    // Need "zero-copy" buffering, i.e. we need
-    // to move buffers when using send!
-    // For the moment we'll clone them and keep track
-    // what what is empty in a stack, (empty_buffers)
+    // The empty buffers, a buffer is moved to the partner
+    // in Messages::Data and moved back in Messages::Empty
     buffers: Vec<Box<Vec<u8>>>,
+
+    // Set when the done state is entered, true if the whole
+    // file was read, false if it couldn't be opened
+    // TODO: Return Error
+    succeeded: bool,
 }
 
-const MAX_STATES: usize = 5;
-const IDX_BASE: usize = 0;
-const IDX_OPEN: usize = 1;
-const IDX_WAIT_FOR_START: usize = 2;
-const IDX_READ: usize = 3;
-const IDX_WAIT_FOR_EMPTY: usize = 4;
+state_ids! {
+    enum States {
+        Base,
+        Open,
+        WaitForStart,
+        Read,
+        WaitForEmpty,
+        Done,
+    }
+}
 
 impl FileStreamProducer {
-    fn new() -> Result<Executor<FileStreamProducer, Messages>, DynError> {
+    fn new() -> Result<Executor<FileStreamProducer, Messages, States>, DynError> {
         let (tx, rx) = channel::<Messages>();
 
         let fsp = RefCell::new(Self {
@@ -80,27 +80,58 @@ impl FileStreamProducer {
             partner_tx: None,
             file: None,
             buffers: Vec::new(),
+            succeeded: false,
         });
 
-        let sme = Executor::new(fsp, MAX_STATES)
-            // IDX_BASE
-            .state(StateInfo::new("base", Self::base))
-            // IDX_OPEN
-            .state(StateInfo::new("open", Self::open).parent_idx(IDX_BASE))
-            // IDX_WAIT_FOR_START
-            .state(StateInfo::new("wait_for_start", Self::wait_for_start).parent_idx(IDX_BASE))
-            // IDX_READ
-            .state(StateInfo::new("read", Self::read).parent_idx(IDX_BASE))
-            // IDX_WAIT_FOR_EMPTY
-            .state(StateInfo::new("wait_for_empty", Self::wait_for_empty).parent_idx(IDX_BASE))
-            .build(IDX_OPEN);
-
-        sme
+        let sme = Executor::new(fsp, States::COUNT)
+            .state_at(
+                States::Base,
+                StateInfo::new_mut("base", Self::base).exit_fn(Self::base_exit),
+            )
+            .state_at(
+                States::Open,
+                StateInfo::new_mut("open", Self::open).parent_idx(States::Base),
+            )
+            .state_at(
+                States::WaitForStart,
+                StateInfo::new("wait_for_start", Self::wait_for_start).parent_idx(States::Base),
+            )
+            .state_at(
+                States::Read,
+                StateInfo::new("read", Self::read).parent_idx(States::Base),
+            )
+            .state_at(
+                States::WaitForEmpty,
+                StateInfo::new("wait_for_empty", Self::wait_for_empty).parent_idx(States::Base),
+            )
+            .state_at(
+                States::Done,
+                StateInfo::new_final("done").parent_idx(States::Base),
+            )
+            // read sends itself Read, dispatch those before the next
+            // message from main
+            .drain_budget(8)
+            .build(States::Open)?;
+
+        Ok(sme)
+    }
+
+    // Release the file when the executor is stopped and drop partner_tx
+    // so the partner sees we're done when its receiver is disconnected.
+    fn base_exit(&mut self, _e: &Executor<Self, Messages, States>, _msg: &Messages) {
+        if self.file.take().is_some() {
+            log::info!("base_exit: closed file");
+        }
+        self.partner_tx = None;
     }
 
     // This is the parent of all states and handles all
     // as best as it can for now :)
-    fn base(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+    fn base(
+        &mut self,
+        e: &Executor<Self, Messages, States>,
+        msg: &mut Messages,
+    ) -> StateResult<States> {
         match msg {
             Messages::Open { .. } => log::info!(
                 "base: Ignoring Messages::Open in state {}",
@@ -118,29 +149,20 @@ impl FileStreamProducer {
                 "base: Messages::Data not supported in {}",
                 e.get_current_state_name()
             ),
-            // Maybe the msg parameters should be "msg: Messages" and we'd consume it
-            // or "msg: &mut Messages" then we could "take" it??
             Messages::Empty { buf } => {
                 log::info!("base: Messages::Empty: &buf[0]: {:p} {:0X?}", &buf[0], *buf);
-                let x = buf.clone();
-                log::info!("base: Messages::Empty:   &x[0]: {:p} {:0X?}", &x[0], x);
-                self.buffers.push(x);
+                self.buffers.push(std::mem::take(buf));
             }
-            Messages::Done { result: _ } => panic!(
-                "base: Messages:Done not supported in {}",
-                e.get_current_state_name()
-            ),
-
-            Messages::StopThread => log::info!(
-                "base: Messages::StopThread IGNORING {}",
-                e.get_current_state_name()
-            ),
         }
 
         (Handled::Yes, None)
     }
 
-    fn open(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+    fn open(
+        &mut self,
+        e: &Executor<Self, Messages, States>,
+        msg: &mut Messages,
+    ) -> StateResult<States> {
         match msg {
             Messages::Open {
                 file_name,
@@ -148,27 +170,19 @@ impl FileStreamProducer {
                 buf_capacity,
                 partner_tx,
             } => {
-                // TODO: I don't want to clone this, I'd like to take ownership
-                // of this partner. Another reason to have the `msg: &Messages`
-                // parameter be `msg: Messages`.
-                self.partner_tx = Some(partner_tx.clone());
-                self.file = match File::open(file_name) {
+                self.partner_tx = partner_tx.take();
+                self.file = match File::open(&file_name) {
                     Ok(file) => {
                         log::info!("open: file_name={}", file_name);
                         Some(file)
                     }
                     Err(why) => {
-                        if let Some(partner_tx) = &self.partner_tx {
-                            if let Err(why_send) = partner_tx.send(Messages::Done { result: false })
-                            {
-                                log::info!("open: couldn't send err: '{why}' to partner_tx because of err: '{why_send}'");
-                            }
-                        } else {
-                            log::info!(
-                                "open: couldn't send err: '{why}' because partner_tx is None"
-                            );
-                        }
-                        return (Handled::Yes, None);
+                        log::info!(
+                            "open: err: '{why}' transition to '{}'",
+                            e.get_state_name(States::Done)
+                        );
+                        self.succeeded = false;
+                        return (Handled::Yes, Some(States::Done));
                     }
                 };
 
@@ -191,29 +205,37 @@ impl FileStreamProducer {
 
                 log::info!(
                     "open: Handled Messages::Open transition to '{}'",
-                    e.get_state_name(IDX_WAIT_FOR_START)
+                    e.get_state_name(States::WaitForStart)
                 );
-                (Handled::Yes, Some(IDX_WAIT_FOR_START))
+                (Handled::Yes, Some(States::WaitForStart))
             }
             _ => (Handled::No, None),
         }
     }
 
-    fn wait_for_start(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+    fn wait_for_start(
+        &mut self,
+        e: &Executor<Self, Messages, States>,
+        msg: &Messages,
+    ) -> StateResult<States> {
         match msg {
             Messages::Start => {
                 e.send(Messages::Read).expect("SNH");
                 log::info!(
                     "wait_for_start: Got Start, tranistion to '{}'",
-                    e.get_state_name(IDX_READ)
+                    e.get_state_name(States::Read)
                 );
-                (Handled::Yes, Some(IDX_READ))
+                (Handled::Yes, Some(States::Read))
             }
             _ => (Handled::No, None),
         }
     }
 
-    fn read(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+    fn read(
+        &mut self,
+        e: &Executor<Self, Messages, States>,
+        msg: &Messages,
+    ) -> StateResult<States> {
         match msg {
             Messages::Read => {
                 if let Some(buf) = self.buffers.pop() {
@@ -236,24 +258,13 @@ impl FileStreamProducer {
                             buf
                         );
                         if count < buf.capacity() {
-                            log::info!("read: EOF");
-                            if let Some(partner_tx) = &self.partner_tx {
-                                log::info!("read: EOF Send {} bytes to partner", buf.len());
-                                partner_tx
-                                    .send(Messages::Done { result: true })
-                                    .expect("SNH");
-                            } else {
-                                panic!(
-                                    "read: EOF SNH sending Messages::Done self.partner_tx is None"
-                                );
-                            }
-
-                            // Read all data back to open
+                            // Read all data, we're done which terminates the executor
                             log::info!(
                                 "read: EOF transitition to '{}'",
-                                e.get_state_name(IDX_OPEN)
+                                e.get_state_name(States::Done)
                             );
-                            (Handled::Yes, Some(IDX_OPEN))
+                            self.succeeded = true;
+                            (Handled::Yes, Some(States::Done))
                         } else {
                             if let Some(partner_tx) = &self.partner_tx {
                                 log::info!("read: Send Data {} to partner", buf.len());
@@ -267,20 +278,20 @@ impl FileStreamProducer {
                             (Handled::Yes, None)
                         }
                     } else {
-                        // No file so we're done, back to IDX_OPEN
+                        // No file so we're done, back to States::Open
                         log::info!(
                             "read: SNH, self.file is NONE, transition to '{}'",
-                            e.get_state_name(IDX_OPEN)
+                            e.get_state_name(States::Open)
                         );
-                        (Handled::Yes, Some(IDX_OPEN))
+                        (Handled::Yes, Some(States::Open))
                     }
                 } else {
                     // There are no buffers, wait for an empty one
                     log::info!(
                         "read: no buffers, transition to '{}'",
-                        e.get_state_name(IDX_WAIT_FOR_EMPTY)
+                        e.get_state_name(States::WaitForEmpty)
                     );
-                    (Handled::Yes, Some(IDX_WAIT_FOR_EMPTY))
+                    (Handled::Yes, Some(States::WaitForEmpty))
                 }
             }
             _ => {
@@ -290,17 +301,21 @@ impl FileStreamProducer {
         }
     }
 
-    fn wait_for_empty(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+    fn wait_for_empty(
+        &mut self,
+        e: &Executor<Self, Messages, States>,
+        msg: &Messages,
+    ) -> StateResult<States> {
         match msg {
             Messages::Empty { .. } => {
                 // Would be "faster" if we handled Empty here but DRY so let base do it.
                 e.send(Messages::Read).expect("SNH");
-                (Handled::No, Some(IDX_READ))
+                (Handled::No, Some(States::Read))
             }
             //Messages::Read => {
             //    // SNH ???
             //    log::info!("wait_for_empty: Read received, defer");
-            //    e.defer_send(msg.clone()).expect("SNH");
+            //    e.defer_current(msg.clone()).expect("SNH");
             //    (Handled::Yes, None)
             //}
             _ => (Handled::No, None),
@@ -314,58 +329,41 @@ fn main() {
 
     let (tx, rx) = channel::<Messages>();
 
-    let mut efsp = FileStreamProducer::new().expect("Error Fsp::new");
+    let efsp = FileStreamProducer::new().expect("Error Fsp::new");
     log::info!("new: fsp={:?}", efsp.get_sm());
 
-    // get tx for efsp
-    let efsp_tx = efsp.clone_sender();
-
     // Spawn efsp in another thread
-    let efsp_thread = thread::spawn(move || {
-        log::info!("efsp thread:+");
-        while let Ok(msg) = efsp.recv() {
-            log::info!("efsp thread:  recv msg={:0X?}", msg);
-            efsp.dispatcher(&msg);
-            match msg {
-                Messages::StopThread => {
-                    log::info!("efsp thread: Stopping");
-                    break;
-                }
-                _ => (),
-            }
-        }
-        log::info!("efsp thread:-");
-    });
+    let efsp_handle = efsp.spawn();
+
+    // get tx for efsp
+    let efsp_tx = efsp_handle.sender();
 
     efsp_tx
         .send(Messages::Open {
             file_name: "hello.txt".to_owned(),
             buf_count: 2,
             buf_capacity: 3,
-            partner_tx: tx,
+            partner_tx: Some(tx),
         })
         .unwrap();
 
     efsp_tx.send(Messages::Start).unwrap();
 
+    // Ends when efsp terminates and drops its partner_tx
     while let Ok(r) = rx.recv() {
         match r {
             Messages::Data { buf } => {
                 log::info!("main: Data {} {:p} {:0X?}", buf.len(), &buf[0], buf);
-                efsp_tx
-                    .send(Messages::Empty { buf: Box::new(buf) })
-                    .unwrap();
-            }
-            Messages::Done { result } => {
-                log::info!("main: Done result={result}");
-                break;
+                // Fails if efsp terminated after sending the Data
+                let _ = efsp_tx.send(Messages::Empty { buf: Box::new(buf) });
             }
             _ => log::info!("main: unexpected msg: {:?}", r),
         }
     }
 
-    efsp_tx.send(Messages::StopThread).unwrap();
-    efsp_thread.join().expect("Error efsp_thread");
+    let fsp = efsp_handle.join().expect("Error efsp_handle");
+    log::info!("main: Done succeeded={}", fsp.succeeded);
+    log::info!("main: fsp={:?}", fsp);
 
     log::info!("main:-");
 }
@@ -2,7 +2,7 @@ use std::{cell::RefCell, sync::mpsc::Sender};
 
 use custom_logger::env_logger_init;
 
-use hsm0_with_executor::{DynError, Executor, Handled, StateInfo, StateResult};
+use hsm0_with_executor::{state_ids, DynError, Executor, Handled, StateInfo, StateResult};
 
 #[derive(Debug, Clone)]
 enum Messages {
@@ -16,20 +16,23 @@ struct SendMsgToSelfSm {
     val: i32,
 }
 
-const MAX_STATES: usize = 2;
-const IDX_BASE: usize = 0;
-const IDX_DONE: usize = 0;
+state_ids! {
+    enum States {
+        Base,
+        Done,
+    }
+}
 
 impl SendMsgToSelfSm {
-    pub fn new(sender: Sender<Messages>) -> Result<Executor<Self, Messages>, DynError> {
+    pub fn new(sender: Sender<Messages>) -> Result<Executor<Self, Messages, States>, DynError> {
         let sm = RefCell::new(SendMsgToSelfSm {
             self_tx: sender,
             val: 0,
         });
-        let sme = Executor::new(sm, MAX_STATES)
-            .state(StateInfo::new("base", Self::base))
-            .state(StateInfo::new("done", Self::done))
-            .build(IDX_BASE)
+        let sme = Executor::new(sm, States::COUNT)
+            .state_at(States::Base, StateInfo::new("base", Self::base))
+            .state_at(States::Done, StateInfo::new("done", Self::done))
+            .build(States::Base)
             .expect("Unexpected error initializing");
 
         log::info!(
@@ -41,7 +44,11 @@ impl SendMsgToSelfSm {
         Ok(sme)
     }
 
-    fn base(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+    fn base(
+        &mut self,
+        _e: &Executor<Self, Messages, States>,
+        msg: &Messages,
+    ) -> StateResult<States> {
         match msg {
             Messages::Value { val } => {
                 log::info!("base Messages::Value:+ val={}", val);
@@ -53,24 +60,28 @@ impl SendMsgToSelfSm {
                         (Handled::Yes, None)
                     } else {
                         log::info!("base Messages::Value:- ERR so DONE self.val={}", self.val);
-                        (Handled::Yes, Some(IDX_DONE))
+                        (Handled::Yes, Some(States::Done))
                     }
                 } else {
                     // We're done
                     self.send_done();
 
                     log::info!("base Messages::Value:- Done self.val={}", self.val);
-                    (Handled::Yes, Some(IDX_DONE))
+                    (Handled::Yes, Some(States::Done))
                 }
             }
             Messages::Done { val: _ } => {
                 self.send_done();
-                (Handled::Yes, Some(IDX_DONE))
+                (Handled::Yes, Some(States::Done))
             }
         }
     }
 
-    fn done(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+    fn done(
+        &mut self,
+        _e: &Executor<Self, Messages, States>,
+        _msg: &Messages,
+    ) -> StateResult<States> {
         // Responsed with Done for any messages
         self.send_done();
         log::info!("base:+- self.val={}", self.val);
@@ -91,14 +102,14 @@ fn main() {
 
     // Dispatch the first message
     let msg = Messages::Value { val: 1 };
-    sme.dispatch(&msg);
+    sme.dispatch(&msg).unwrap();
 
     // Receive messages until SendMsgToSelfSm reports Done or rx is closed
     while let Ok(m) = rx.recv() {
         match m {
             Messages::Value { val: _ } => {
                 // Dispatch the message received
-                sme.dispatch(&m);
+                sme.dispatch(&m).unwrap();
             }
             Messages::Done { val } => {
                 log::info!("main: Done val={val}");
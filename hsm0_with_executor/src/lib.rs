@@ -1,16 +1,282 @@
 #![feature(no_coverage)]
 
 use std::{
-    cell::RefCell,
-    collections::VecDeque,
-    fmt::Debug,
-    sync::mpsc::{Receiver, RecvError, SendError, Sender, TryRecvError},
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Debug, Display},
+    ops::{Deref, DerefMut},
+    sync::{
+        mpsc::{Receiver, RecvError, RecvTimeoutError, SendError, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+mod observer;
+mod record;
+mod run;
+pub mod sim;
+mod snapshot;
+mod timer;
+pub use observer::{Observer, StateRef, TraceObserver};
+use record::Recorder;
+pub use record::{Divergence, RecordEntry, Recordable, ReplayError};
+use run::Envelope;
+pub use run::{ExecutorHandle, MsgSender};
+pub use snapshot::{DeferredSnapshot, RestoreError, Snapshot, StateSnapshot};
+pub use state_result::diagram::Diagram;
+use timer::Timers;
+pub use timer::{Clock, ManualClock, SystemClock, TimerError, TimerId};
+
 pub type DynError = Box<dyn std::error::Error>;
-type ProcessFn<SM, P> = fn(&mut SM, &Executor<SM, P>, &P) -> StateResult;
-type EnterFn<SM, P> = fn(&mut SM, &P);
-type ExitFn<SM, P> = fn(&mut SM, &P);
+
+// Errors returned by Executor::build when the states don't
+// describe a valid state machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    // No states were added
+    NoStates,
+
+    // A state wasn't added for each id, see StateId::COUNT
+    MissingStates {
+        count: usize,
+        len: usize,
+    },
+
+    // A state was added at an index different from its id
+    IdMismatch {
+        state: String,
+        id: usize,
+        idx: usize,
+    },
+
+    // Two states were added with the same id, see Executor::state_at
+    DuplicateId {
+        state: String,
+        id: usize,
+    },
+
+    // The parent of a state isn't the index of a state
+    ParentOutOfRange {
+        state: String,
+        idx_parent: usize,
+    },
+
+    // Two states have the same name
+    DuplicateName {
+        name: String,
+        idx_first: usize,
+        idx_second: usize,
+    },
+
+    // The parents of these states form a cycle
+    Cycle {
+        states: Vec<String>,
+    },
+
+    // The initial state isn't the index of a state
+    InitialStateOutOfRange {
+        idx_initial: usize,
+        len: usize,
+    },
+
+    // The initial state isn't a transition target, a leaf or a
+    // state with an initial_child
+    InitialStateNotTarget {
+        state: String,
+        targets: Vec<String>,
+    },
+
+    // The error state of InvalidTransitionPolicy::ErrorState or
+    // DeferOverflowPolicy::ErrorState isn't a transition target
+    InvalidErrorState {
+        idx_error_state: usize,
+        targets: Vec<String>,
+    },
+
+    // A parallel state needs two or more regions (children)
+    TooFewRegions {
+        state: String,
+        regions: usize,
+    },
+
+    // A history state needs a parent and can't have children
+    InvalidHistory {
+        state: String,
+    },
+
+    // The initial child of a state isn't one of its children,
+    // or the state is parallel
+    InvalidInitialChild {
+        state: String,
+        idx_child: usize,
+    },
+
+    // A final state can't have children
+    FinalWithChildren {
+        state: String,
+    },
+
+    // The target of a transition table rule isn't a transition target
+    InvalidRuleTarget {
+        state: String,
+        label: String,
+        idx_target: usize,
+    },
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::NoStates => write!(f, "No states"),
+            BuildError::MissingStates { count, len } => {
+                write!(f, "There are {count} state ids but {len} states were added")
+            }
+            BuildError::IdMismatch { state, id, idx } => {
+                write!(
+                    f,
+                    "State '{state}' has id {id} but is at index {idx}"
+                )
+            }
+            BuildError::DuplicateId { state, id } => {
+                write!(f, "State '{state}' has id {id} which is already used")
+            }
+            BuildError::ParentOutOfRange { state, idx_parent } => write!(
+                f,
+                "State '{state}' has parent {idx_parent} which is not a valid state"
+            ),
+            BuildError::DuplicateName {
+                name,
+                idx_first,
+                idx_second,
+            } => write!(
+                f,
+                "State name '{name}' is used by states {idx_first} and {idx_second}"
+            ),
+            BuildError::Cycle { states } => write!(f, "Cycle detected: {}", states.join(", ")),
+            BuildError::InitialStateOutOfRange { idx_initial, len } => write!(
+                f,
+                "{idx_initial} is not a valid initial state, there are only {len} states"
+            ),
+            BuildError::InitialStateNotTarget { state, targets } => write!(
+                f,
+                "'{state}' is not a valid initial state, only {targets:?} are allowed"
+            ),
+            BuildError::InvalidErrorState {
+                idx_error_state,
+                targets,
+            } => write!(
+                f,
+                "{idx_error_state} is not a valid error state, only {targets:?} are allowed"
+            ),
+            BuildError::TooFewRegions { state, regions } => write!(
+                f,
+                "Parallel state '{state}' has {regions} regions, at least 2 are needed"
+            ),
+            BuildError::InvalidHistory { state } => write!(
+                f,
+                "History state '{state}' must have a parent and no children"
+            ),
+            BuildError::InvalidInitialChild { state, idx_child } => write!(
+                f,
+                "{idx_child} is not a valid initial child of state '{state}'"
+            ),
+            BuildError::FinalWithChildren { state } => {
+                write!(f, "Final state '{state}' must not have children")
+            }
+            BuildError::InvalidRuleTarget {
+                state,
+                label,
+                idx_target,
+            } => write!(
+                f,
+                "Rule '{label}' of state '{state}' has target {idx_target} which is not a valid transition target"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// A state returned a transition to a state that isn't a leaf or
+// isn't a state at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTransition {
+    // The state that returned the transition
+    pub idx_src: usize,
+    pub src: String,
+
+    // The destination that was returned
+    pub idx_dest: usize,
+
+    // The message being processed, formatted with Debug
+    pub msg: String,
+}
+
+impl Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "State '{}' returned {} which is not a valid transition target, msg={}",
+            self.src, self.idx_dest, self.msg
+        )
+    }
+}
+
+// A message that no state handled, see Executor::dead_letter_capacity
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    // The state that was current when the message was dispatched
+    pub idx_state: usize,
+    pub state: String,
+
+    // The message, formatted with Debug
+    pub msg: String,
+}
+
+// Errors returned by Executor::dispatch and Executor::dispatcher
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DispatchError {
+    // Only returned with InvalidTransitionPolicy::ReturnError
+    InvalidTransition(InvalidTransition),
+
+    // A state created with StateInfo::new_mut was reached while
+    // dispatching a &P, use dispatch_mut or dispatcher_mut.
+    MutProcessWithRef { idx: usize, state: String },
+
+    // Writing the message with seq to the recording failed,
+    // see Executor::record
+    Record { seq: u64, error: String },
+}
+
+impl Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DispatchError::InvalidTransition(it) => write!(f, "{it}"),
+            DispatchError::MutProcessWithRef { idx, state } => write!(
+                f,
+                "State '{state}' at index {idx} needs a &mut msg but was dispatched a &msg"
+            ),
+            DispatchError::Record { seq, error } => {
+                write!(f, "Recording seq {seq} failed: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DispatchError {}
+
+type ProcessFn<SM, P, S> = fn(&mut SM, &Executor<SM, P, S>, &P) -> StateResult<S>;
+type ProcessMutFn<SM, P, S> = fn(&mut SM, &Executor<SM, P, S>, &mut P) -> StateResult<S>;
+type CompletionFn<SM, P, S> = fn(&mut SM, &Executor<SM, P, S>, &P) -> StateResult<S>;
+type EnterFn<SM, P, S> = fn(&mut SM, &Executor<SM, P, S>, &P);
+type ExitFn<SM, P, S> = fn(&mut SM, &Executor<SM, P, S>, &P);
+type TransitionActionFn<SM, P, S> = fn(&mut SM, &Executor<SM, P, S>, &P);
+type EventFn<P> = fn(&P) -> bool;
+type CloneFn<P> = fn(&P) -> P;
+type GuardFn<SM, P> = fn(&SM, &P) -> bool;
+type InvalidTransitionFn<SM, P, S> = fn(&mut SM, &Executor<SM, P, S>, &InvalidTransition, &P);
+type UnhandledFn<SM, P, S> = fn(&mut SM, &Executor<SM, P, S>, &P);
 
 pub enum Handled {
     Yes,
@@ -19,31 +285,360 @@ pub enum Handled {
 
 pub type Transition = usize;
 
-pub type StateResult = (Handled, Option<Transition>);
+pub type StateResult<S = Transition> = (Handled, Option<S>);
+
+// Identifies a state. The default is a raw `usize` index, but a
+// typed identifier created with `state_ids!` is preferred as a
+// misspelled or out of range state is then a compile error.
+pub trait StateId: Copy + Debug {
+    // The number of ids, Executor::build checks a state was added
+    // for each. It's 0 when unknown, as for usize, and not checked.
+    const COUNT: usize;
+
+    // The index of the state in Executor::states
+    fn idx(self) -> usize;
+}
+
+impl StateId for usize {
+    const COUNT: usize = 0;
+
+    fn idx(self) -> usize {
+        self
+    }
+}
+
+// Declare an enum whose variants are the states of an Executor.
+//
+// Add the states with Executor::state_at, each state is placed at
+// the index of its id so the order of the state_at calls doesn't
+// matter. Executor::state, which places a state at the next index,
+// is only available for usize ids.
+//
+// ```ignore
+// state_ids! {
+//     enum States {
+//         Base,
+//         Initial,
+//     }
+// }
+// ```
+#[macro_export]
+macro_rules! state_ids {
+    ($(#[$attr:meta])* $vis:vis enum $name:ident { $($state:ident),+ $(,)? }) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        $vis enum $name {
+            $($state),+
+        }
+
+        #[allow(unused)]
+        impl $name {
+            // Number of states, use as max_states in Executor::new
+            $vis const COUNT: usize = <Self as $crate::StateId>::COUNT;
+        }
+
+        impl $crate::StateId for $name {
+            const COUNT: usize = [$($name::$state),+].len();
+
+            fn idx(self) -> usize {
+                self as usize
+            }
+        }
+    };
+}
+
+// What dispatch does when a state returns a transition to a
+// state that isn't a valid transition target.
+pub enum InvalidTransitionPolicy<SM, P, S = Transition> {
+    // Panic, this is the default
+    Panic,
+
+    // The transition is ignored and dispatch returns
+    // DispatchError::InvalidTransition
+    ReturnError,
+
+    // Transition to this state instead, it must be a leaf
+    ErrorState(S),
+
+    // The transition is ignored and the fn is invoked
+    Hook(InvalidTransitionFn<SM, P, S>),
+}
+
+// What defer_send does when there are already defer_capacity
+// deferred messages, see Executor::defer_capacity
+pub enum DeferOverflowPolicy<S = Transition> {
+    // defer_send returns Err with the message, this is the default
+    Reject,
+
+    // The oldest deferred message is dropped
+    DropOldest,
+
+    // The message is dropped
+    DropNewest,
+
+    // The message is dropped and the executor transitions to this
+    // state once the process fn returns, it must be a leaf. Outside
+    // of a dispatch the message is only dropped.
+    ErrorState(S),
+}
+
+// Stamped on every message an Executor accepts, see
+// Executor::current_msg_info and Observer::on_dispatch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsgInfo {
+    // Increases by one for each message accepted by send,
+    // the dispatchers or defer_send. A message deferred with
+    // StateInfo::defer or Executor::defer_current keeps the
+    // seq it was accepted with.
+    pub seq: u64,
+
+    // The clock's time when the message was accepted
+    pub enqueued_at: Instant,
+}
+
+// A deferred message, the info it was accepted with and how
+// many times it's been replayed and deferred again
+struct Deferred<P> {
+    msg: P,
+    info: MsgInfo,
+    replays: usize,
+}
+
+// Returned by the dispatchers, see Executor::status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Running,
+
+    // The active leafs are all final states
+    Terminated,
+}
+
+// How a transition to a state that's active is made, see
+// Executor::transition_kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransitionKind {
+    // The target is exited and entered again, for a target that's
+    // an ancestor of the current state that includes its descendants
+    #[default]
+    External,
+
+    // Nothing is exited or entered and the current state is unchanged,
+    // if the target isn't active this is the same as External
+    Internal,
+}
+
+// Which states a transition to a history state enters, see
+// StateInfo::new_history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum History {
+    // The last active child of the parent, entered at its initial child
+    Shallow,
+
+    // The last active child of the parent and, recursively,
+    // the last active children of it and its descendants
+    Deep,
+}
+
+// The process fn of history states, they are never active so it
+// only runs if a message is dispatched to one directly, and of
+// states created with new_table or new_final.
+fn process_not_handled<SM, P, S>(
+    _sm: &mut SM,
+    _e: &Executor<SM, P, S>,
+    _msg: &P,
+) -> StateResult<S> {
+    (Handled::No, None)
+}
+
+// An entry of a state's transition table, see StateInfo::transition.
+//
+// The rule matches a message if event returns true for it and the
+// guard, if any, returns true. A matching rule handles the message,
+// with a target it transitions there running action as the
+// transition action, otherwise action runs immediately.
+pub struct TransitionRule<SM, P, S = Transition> {
+    // Shown as the label of the transition in diagrams
+    pub label: String,
+    pub event: EventFn<P>,
+    pub guard: Option<GuardFn<SM, P>>,
+    pub action: Option<TransitionActionFn<SM, P, S>>,
+    pub target: Option<usize>,
+    pub kind: TransitionKind,
+}
+
+impl<SM, P, S: StateId> TransitionRule<SM, P, S> {
+    // A rule handling the messages event returns true for
+    // without a transition until a target is set
+    pub fn new(label: &str, event: EventFn<P>) -> Self {
+        TransitionRule {
+            label: label.to_owned(),
+            event,
+            guard: None,
+            action: None,
+            target: None,
+            kind: TransitionKind::External,
+        }
+    }
+
+    pub fn guard(mut self, guard: GuardFn<SM, P>) -> Self {
+        self.guard = Some(guard);
+
+        self
+    }
+
+    pub fn action(mut self, action: TransitionActionFn<SM, P, S>) -> Self {
+        self.action = Some(action);
+
+        self
+    }
+
+    pub fn target(mut self, target: S) -> Self {
+        self.target = Some(target.idx());
+
+        self
+    }
+
+    pub fn kind(mut self, kind: TransitionKind) -> Self {
+        self.kind = kind;
+
+        self
+    }
+}
+
+// The process fn of a state.
+//
+// A Mut process fn may modify or take the payload of the message,
+// for instance with std::mem::take, to move it without copying.
+// If it returns Handled::No its parents see the message as it was
+// left and exit fns run during the same dispatch see it too.
+pub enum Process<SM, P, S = Transition> {
+    Ref(ProcessFn<SM, P, S>),
+    Mut(ProcessMutFn<SM, P, S>),
+}
+
+impl<SM, P, S> Clone for Process<SM, P, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<SM, P, S> Copy for Process<SM, P, S> {}
+
+// The message being dispatched
+enum MsgRef<'a, P> {
+    Ref(&'a P),
+    Mut(&'a mut P),
+}
+
+impl<P> MsgRef<'_, P> {
+    fn get(&self) -> &P {
+        match self {
+            MsgRef::Ref(msg) => msg,
+            MsgRef::Mut(msg) => msg,
+        }
+    }
+}
 
 //#[derive(Clone)]
-pub struct StateInfo<SM, P> {
+pub struct StateInfo<SM, P, S = Transition> {
     pub name: String,
+
+    // Set by Executor::state_at
+    id: Option<usize>,
     pub parent: Option<usize>,
-    pub enter: Option<EnterFn<SM, P>>,
-    pub process: ProcessFn<SM, P>,
-    pub exit: Option<ExitFn<SM, P>>,
+    pub enter: Option<EnterFn<SM, P, S>>,
+    pub process: Process<SM, P, S>,
+    pub exit: Option<ExitFn<SM, P, S>>,
     pub active: bool,
+
+    // The children of a parallel state are its regions, see parallel
+    pub parallel: bool,
+
+    // Some if this is a history pseudo-state, see new_history
+    pub history: Option<History>,
+
+    // The child entered when this state is entered without a
+    // transition to one of its descendants, see initial_child
+    pub initial_child: Option<usize>,
+
+    // Evaluated in order before the process fn, see transition
+    pub transitions: Vec<TransitionRule<SM, P, S>>,
+
+    // See new_final and completion_fn
+    pub is_final: bool,
+    pub completion: Option<CompletionFn<SM, P, S>>,
+
+    // The messages this state defers and how they're cloned, see defer
+    pub defers: Vec<EventFn<P>>,
+    pub defer_clone: Option<CloneFn<P>>,
+
+    // The child that was active when this state's
+    // children were last exited, used by history states
+    pub last_active_child: Option<usize>,
     pub children_for_cycle_detector: Vec<usize>,
     pub enter_cnt: usize,
     pub process_cnt: usize,
     pub exit_cnt: usize,
 }
 
-impl<SM, P> StateInfo<SM, P> {
-    pub fn new(name: &str, process_fn: ProcessFn<SM, P>) -> Self {
+impl<SM, P, S: StateId> StateInfo<SM, P, S> {
+    pub fn new(name: &str, process_fn: ProcessFn<SM, P, S>) -> Self {
+        Self::with_process(name, Process::Ref(process_fn))
+    }
+
+    // A state whose process fn gets a &mut msg, the messages must be
+    // dispatched with dispatch_mut, dispatcher_mut or dispatcher_owned.
+    pub fn new_mut(name: &str, process_fn: ProcessMutFn<SM, P, S>) -> Self {
+        Self::with_process(name, Process::Mut(process_fn))
+    }
+
+    // A history pseudo-state, it must have a parent and no children.
+    // A transition to it enters the parent at the child that was
+    // last active, or if the parent's children weren't exited yet,
+    // at its initial child. For History::Deep the descendants of that
+    // child are entered at their last active children too.
+    pub fn new_history(name: &str, history: History) -> Self {
+        let mut state = Self::with_process(name, Process::Ref(process_not_handled));
+        state.history = Some(history);
+
+        state
+    }
+
+    // A state whose messages are only handled by its transition table
+    pub fn new_table(name: &str) -> Self {
+        Self::with_process(name, Process::Ref(process_not_handled))
+    }
+
+    // A final state, it must not have children. Entering it completes
+    // its parent, when all the active leafs are final states the
+    // executor is terminated. Unlike other states it's entered by the
+    // dispatch that made the transition to it.
+    pub fn new_final(name: &str) -> Self {
+        let mut state = Self::with_process(name, Process::Ref(process_not_handled));
+        state.is_final = true;
+
+        state
+    }
+
+    fn with_process(name: &str, process: Process<SM, P, S>) -> Self {
         StateInfo {
             name: name.to_owned(),
+            id: None,
             parent: None,
             enter: None,
-            process: process_fn,
+            process,
             exit: None,
             active: false,
+            parallel: false,
+            history: None,
+            initial_child: None,
+            transitions: Vec::new(),
+            is_final: false,
+            completion: None,
+            defers: Vec::new(),
+            defer_clone: None,
+            last_active_child: None,
             children_for_cycle_detector: Vec::<usize>::new(),
             enter_cnt: 0,
             process_cnt: 0,
@@ -51,26 +646,89 @@ impl<SM, P> StateInfo<SM, P> {
         }
     }
 
-    pub fn enter_fn(mut self, enter_fn: EnterFn<SM, P>) -> Self {
+    pub fn enter_fn(mut self, enter_fn: EnterFn<SM, P, S>) -> Self {
         self.enter = Some(enter_fn);
 
         self
     }
 
-    pub fn exit_fn(mut self, exit_fn: EnterFn<SM, P>) -> Self {
+    pub fn exit_fn(mut self, exit_fn: ExitFn<SM, P, S>) -> Self {
         self.exit = Some(exit_fn);
 
         self
     }
 
-    pub fn parent_idx(mut self, idx_parent: usize) -> Self {
-        self.parent = Some(idx_parent);
+    // Invoked with the message being dispatched when this state is
+    // completed, i.e. its active child, or for a parallel state the
+    // active children of all its regions, are final states. The
+    // transition it returns is made, Handled is ignored.
+    pub fn completion_fn(mut self, completion_fn: CompletionFn<SM, P, S>) -> Self {
+        self.completion = Some(completion_fn);
+
+        self
+    }
+
+    pub fn parent_idx(mut self, idx_parent: S) -> Self {
+        self.parent = Some(idx_parent.idx());
+
+        self
+    }
+
+    // Make this a parallel state, each child is a region that is
+    // active at the same time as the others. Entering a parallel
+    // state enters all its regions in the order they were added,
+    // a region not containing the transition target is entered at
+    // its initial child. Every region is offered each message and
+    // the parallel state only processes it if no region handled
+    // it. Exiting exits the regions in the reverse order.
+    pub fn parallel(mut self) -> Self {
+        self.parallel = true;
+
+        self
+    }
+
+    // The child entered when this state is entered other than by a
+    // transition to one of its descendants, the default is the first
+    // child. Declaring it makes this state a valid transition target,
+    // a transition to it drills down through the initial children to
+    // a leaf, which becomes the current state.
+    pub fn initial_child(mut self, idx_child: S) -> Self {
+        self.initial_child = Some(idx_child.idx());
+
+        self
+    }
+
+    // Append a rule to the transition table. When a message is
+    // processed by this state the first matching rule handles it
+    // and the process fn isn't invoked, if no rule matches the
+    // process fn is.
+    pub fn transition(mut self, rule: TransitionRule<SM, P, S>) -> Self {
+        self.transitions.push(rule);
+
+        self
+    }
+
+    // Defer the messages event matches while this state is active, they
+    // aren't processed but are deferred as if by Executor::defer_current.
+    // Unlike a UML deferrable trigger this is decided before any state
+    // processes the message, so a substate of the deferring state never
+    // sees it even if it would handle it.
+    // After a transition only the deferred messages that no active
+    // state defers are replayed, the others stay deferred. A message
+    // the defer overflow policy rejects is dropped, see
+    // Executor::defer_dropped, it's never processed instead.
+    pub fn defer(mut self, event: EventFn<P>) -> Self
+    where
+        P: Clone,
+    {
+        self.defers.push(event);
+        self.defer_clone = Some(P::clone);
 
         self
     }
 }
 
-pub struct Executor<SM, P> {
+pub struct Executor<SM, P, S = Transition> {
     //pub name: String, // TODO: add StateMachineInfo::name
 
     // Field `sm` needs "interior mutability" because we pass &mut sm and &Self
@@ -83,9 +741,11 @@ pub struct Executor<SM, P> {
     //     mutable borrow later used by call
     pub sm: RefCell<SM>,
 
-    pub states: Vec<StateInfo<SM, P>>,
+    pub states: Vec<StateInfo<SM, P, S>>,
     pub current_state_changed: bool,
     pub idx_transition_dest: Option<usize>,
+    pub idx_transition_src: usize,
+    pub idx_initial_state: usize,
     pub idx_current_state: usize,
     pub idx_previous_state: usize,
     pub idxs_enter_fns: Vec<usize>,
@@ -95,62 +755,341 @@ pub struct Executor<SM, P> {
     pub transition_targets: Vec<usize>,
 
     // Returns `true` if array idx is in transition_targets
+    // or is a state with an initial child
     pub transition_targets_set: Vec<bool>,
 
-    // Defer support
-    primary_tx: Sender<P>,
-    primary_rx: Receiver<P>,
-    defer_tx: [Sender<P>; 2],
-    defer_rx: [Receiver<P>; 2],
+    // The children of each state in the order they were added
+    children: Vec<Vec<usize>>,
+
+    invalid_transition_policy: InvalidTransitionPolicy<SM, P, S>,
+
+    // Unhandled message support, dead_letters holds at most
+    // dead_letter_capacity messages dropping the oldest
+    unhandled_fn: Option<UnhandledFn<SM, P, S>>,
+    unhandled_cnt: u64,
+    dead_letters: VecDeque<DeadLetter>,
+    dead_letter_capacity: usize,
+    dead_letters_dropped: u64,
+
+    primary_tx: MsgSender<P>,
+    primary_rx: Receiver<Envelope<P>>,
+
+    // Drain support, stop_received is set when draining received
+    // an Envelope::Stop so the next recv returns Err
+    drain_budget: Option<usize>,
+    drain_exhausted: u64,
+    stop_received: Cell<bool>,
+
+    // Defer support, messages are deferred to deferred[current_defer_idx]
+    // and replayed from the other queue
+    deferred: RefCell<[VecDeque<Deferred<P>>; 2]>,
     current_defer_idx: usize,
+    defer_capacity: Option<usize>,
+    defer_overflow_policy: DeferOverflowPolicy<S>,
+    defer_max_replays: Option<usize>,
+    defer_max_age: Option<Duration>,
+    defer_overflows: Cell<u64>,
+    defer_dropped: Cell<u64>,
+    defer_expired: u64,
+
+    // Set by defer_send for DeferOverflowPolicy::ErrorState
+    defer_overflowed: Cell<bool>,
+
+    // The replays of the deferred message being replayed, kept
+    // if it's deferred again with defer_current
+    replaying: Cell<Option<usize>>,
+
+    // The info of the message being dispatched
+    current_msg_info: Cell<Option<MsgInfo>>,
+
+    // Timer support
+    clock: Arc<dyn Clock>,
+    timers: RefCell<Timers<P>>,
+
+    // The state whose enter or process fn is running, owns
+    // the timers started with state_send_after
+    idx_running_state: Cell<Option<usize>>,
+
+    // Set by transition_action and transition_kind while a process
+    // fn is running, they are moved to transition_dest_action and
+    // transition_dest_kind if the fn returned the first transition
+    transition_action: Cell<Option<TransitionActionFn<SM, P, S>>>,
+    transition_dest_action: Option<TransitionActionFn<SM, P, S>>,
+    transition_kind: Cell<TransitionKind>,
+    transition_dest_kind: TransitionKind,
+
+    observers: RefCell<Vec<Box<dyn Observer<P> + Send>>>,
+
+    // Some while recording or replaying
+    recorder: Option<Recorder<P>>,
 }
 
 impl<SM, P> Executor<SM, P>
 where
     SM: Debug,
     P: Debug,
+{
+    // Add a state to the the executor at the next index, with
+    // typed state ids use state_at
+    pub fn state(mut self, state_info: StateInfo<SM, P>) -> Self {
+        self.states.push(state_info);
+
+        self
+    }
+}
+
+impl<SM, P, S> Executor<SM, P, S>
+where
+    SM: Debug,
+    P: Debug,
+    S: StateId,
 {
     // Begin building an executor.
     //
     // You must call add_state to add one or more states
     pub fn new(sm: RefCell<SM>, max_states: usize) -> Self {
-        let (primary_tx, primary_rx) = std::sync::mpsc::channel::<P>();
-        let (defer0_tx, defer0_rx) = std::sync::mpsc::channel::<P>();
-        let (defer1_tx, defer1_rx) = std::sync::mpsc::channel::<P>();
+        let (primary_tx, primary_rx) = std::sync::mpsc::channel::<Envelope<P>>();
 
         Executor {
             sm,
-            states: Vec::<StateInfo<SM, P>>::with_capacity(max_states),
+            states: Vec::<StateInfo<SM, P, S>>::with_capacity(max_states),
             current_state_changed: true,
             idx_transition_dest: None,
+            idx_transition_src: 0,
+            idx_initial_state: 0,
             idx_current_state: 0,
             idx_previous_state: 0,
             idxs_enter_fns: Vec::<usize>::with_capacity(max_states),
             idxs_exit_fns: VecDeque::<usize>::with_capacity(max_states),
             transition_targets: Vec::<usize>::with_capacity(max_states),
             transition_targets_set: Vec::<bool>::with_capacity(max_states),
-            primary_tx,
+            children: Vec::<Vec<usize>>::with_capacity(max_states),
+            invalid_transition_policy: InvalidTransitionPolicy::Panic,
+            unhandled_fn: None,
+            unhandled_cnt: 0,
+            dead_letters: VecDeque::new(),
+            dead_letter_capacity: 0,
+            dead_letters_dropped: 0,
+            primary_tx: MsgSender::new(primary_tx),
             primary_rx,
-            defer_tx: [defer0_tx, defer1_tx],
-            defer_rx: [defer0_rx, defer1_rx],
+            drain_budget: None,
+            drain_exhausted: 0,
+            stop_received: Cell::new(false),
+            deferred: RefCell::new([VecDeque::new(), VecDeque::new()]),
             current_defer_idx: 0,
+            defer_capacity: None,
+            defer_overflow_policy: DeferOverflowPolicy::Reject,
+            defer_max_replays: None,
+            defer_max_age: None,
+            defer_overflows: Cell::new(0),
+            defer_dropped: Cell::new(0),
+            defer_expired: 0,
+            defer_overflowed: Cell::new(false),
+            replaying: Cell::new(None),
+            current_msg_info: Cell::new(None),
+            clock: Arc::new(SystemClock),
+            timers: RefCell::new(Timers::new()),
+            idx_running_state: Cell::new(None),
+            transition_action: Cell::new(None),
+            transition_dest_action: None,
+            transition_kind: Cell::new(TransitionKind::External),
+            transition_dest_kind: TransitionKind::External,
+            observers: RefCell::new(Vec::new()),
+            recorder: None,
         }
     }
 
-    // Add a state to the the executor
-    pub fn state(mut self, state_info: StateInfo<SM, P>) -> Self {
+    // Set the clock used by timers, the default is SystemClock,
+    // pending timers keep the time remaining until they expire
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        let now = self.clock.now();
+        self.timers.get_mut().rebase(now, clock.now());
+        self.primary_tx.set_clock(clock.clone());
+        self.clock = clock;
+
+        self
+    }
+
+    // Add a state to the executor at the index of id
+    pub fn state_at(mut self, id: S, mut state_info: StateInfo<SM, P, S>) -> Self {
+        state_info.id = Some(id.idx());
         self.states.push(state_info);
 
         self
     }
 
+    // Set what dispatch does with an invalid transition, the
+    // default is InvalidTransitionPolicy::Panic
+    pub fn invalid_transition_policy(mut self, policy: InvalidTransitionPolicy<SM, P, S>) -> Self {
+        self.invalid_transition_policy = policy;
+
+        self
+    }
+
+    // Invoked with a message that the root state returned Handled::No
+    // for, it may not transition. See unhandled_cnt.
+    pub fn unhandled_fn(mut self, unhandled_fn: UnhandledFn<SM, P, S>) -> Self {
+        self.unhandled_fn = Some(unhandled_fn);
+
+        self
+    }
+
+    // After a message is dispatched the dispatchers also dispatch
+    // the messages already sent to the executor, including those
+    // sent by the process fns, until none are left, the machine
+    // terminates or budget messages have been dispatched. This
+    // drives the machine to a stable state while the budget keeps
+    // a state sending itself messages from starving the caller,
+    // see drain_exhausted. Simulation::add disables draining.
+    pub fn drain_budget(mut self, budget: usize) -> Self {
+        self.drain_budget = Some(budget);
+
+        self
+    }
+
+    // Keep the last capacity unhandled messages as DeadLetters, see
+    // take_dead_letters. The default is 0, none are kept.
+    pub fn dead_letter_capacity(mut self, capacity: usize) -> Self {
+        self.dead_letter_capacity = capacity;
+        self.dead_letters = VecDeque::with_capacity(capacity);
+
+        self
+    }
+
+    // Limit the number of deferred messages, the default is no limit.
+    // See defer_overflow_policy for what happens when it's reached.
+    pub fn defer_capacity(mut self, capacity: usize) -> Self {
+        self.defer_capacity = Some(capacity);
+
+        self
+    }
+
+    // Set what defer_send does when defer_capacity is reached, the
+    // default is DeferOverflowPolicy::Reject
+    pub fn defer_overflow_policy(mut self, policy: DeferOverflowPolicy<S>) -> Self {
+        self.defer_overflow_policy = policy;
+
+        self
+    }
+
+    // Drop a deferred message instead of replaying it once it has
+    // been replayed max_replays times, see defer_expired
+    pub fn defer_max_replays(mut self, max_replays: usize) -> Self {
+        self.defer_max_replays = Some(max_replays);
+
+        self
+    }
+
+    // Drop a deferred message instead of replaying it once max_age
+    // has passed since it was accepted, see defer_expired and
+    // MsgInfo::enqueued_at
+    pub fn defer_max_age(mut self, max_age: Duration) -> Self {
+        self.defer_max_age = Some(max_age);
+
+        self
+    }
+
     // Initialize and make the executor ready to dispatch messages.
     //
-    // The first state will be the state at idx_initial_state
-    pub fn build(mut self, idx_initial_state: usize) -> Result<Self, DynError> {
+    // The first state will be the state at initial_state
+    pub fn build(mut self, initial_state: S) -> Result<Self, BuildError> {
+        let idx_initial_state = initial_state.idx();
+
+        if self.states.is_empty() {
+            return Err(BuildError::NoStates);
+        }
+
+        // Validate there's a state for each id
+        if S::COUNT != 0 && self.states.len() != S::COUNT {
+            return Err(BuildError::MissingStates {
+                count: S::COUNT,
+                len: self.states.len(),
+            });
+        }
+
+        // Validate the ids are unique
+        let mut ids = HashSet::<usize>::with_capacity(self.states.len());
+        for state in self.states.iter() {
+            if let Some(id) = state.id {
+                if !ids.insert(id) {
+                    return Err(BuildError::DuplicateId {
+                        state: state.name.clone(),
+                        id,
+                    });
+                }
+            }
+        }
+
+        // Place the states at their ids, see state_at
+        if self.states.iter().all(|state| state.id.is_some()) {
+            self.states.sort_by_key(|state| state.id);
+        }
+
+        let mut names = HashMap::<&str, usize>::with_capacity(self.states.len());
+        for (idx, state) in self.states.iter().enumerate() {
+            // Validate each state is at its id, ids may be missing
+            if let Some(id) = state.id {
+                if id != idx {
+                    return Err(BuildError::IdMismatch {
+                        state: state.name.clone(),
+                        id,
+                        idx,
+                    });
+                }
+            }
+
+            // Validate the parent is a state
+            if let Some(idx_parent) = state.parent {
+                if idx_parent >= self.states.len() {
+                    return Err(BuildError::ParentOutOfRange {
+                        state: state.name.clone(),
+                        idx_parent,
+                    });
+                }
+            }
+
+            // Validate the names are unique
+            if let Some(idx_first) = names.insert(&state.name, idx) {
+                return Err(BuildError::DuplicateName {
+                    name: state.name.clone(),
+                    idx_first,
+                    idx_second: idx,
+                });
+            }
+        }
+
         // Initialize StateInfo.children_for_cycle_dector for each state
         self.initialize_children();
 
+        // Validate the history states, they are excluded from children
+        // as they are never entered, and the final states
+        for state in self.states.iter() {
+            if state.is_final && !state.children_for_cycle_detector.is_empty() {
+                return Err(BuildError::FinalWithChildren {
+                    state: state.name.clone(),
+                });
+            }
+            if state.history.is_some()
+                && (state.parent.is_none() || !state.children_for_cycle_detector.is_empty())
+            {
+                return Err(BuildError::InvalidHistory {
+                    state: state.name.clone(),
+                });
+            }
+        }
+        self.children = self
+            .states
+            .iter()
+            .map(|state| {
+                state
+                    .children_for_cycle_detector
+                    .iter()
+                    .copied()
+                    .filter(|idx| self.states[*idx].history.is_none())
+                    .collect()
+            })
+            .collect();
+
         // Initialize transition_targets_set to false
         for _ in 0..self.states.len() {
             self.transition_targets_set.push(false);
@@ -168,48 +1107,120 @@ where
         //println!("transition_targets: {:?}", self.transition_targets);
         //println!("transition_targets_set: {:?}", self.transition_targets_set);
 
-        if self.cycle_detector() {
-            return Err("Cycle detected".into());
+        let idxs_cycle = self.cycle_detector();
+        if !idxs_cycle.is_empty() {
+            return Err(BuildError::Cycle {
+                states: self.state_names(&idxs_cycle),
+            });
         }
 
-        // Validate idx_initial_state is valid.
-        if idx_initial_state >= self.states.len() || !self.transition_targets_set[idx_initial_state]
-        {
-            panic!(
-                "{idx_initial_state} is not a valid initial state, only {:?} are allowed",
-                self.transition_targets
-            );
+        // Validate the parallel states have regions
+        for (state, children) in self.states.iter().zip(self.children.iter()) {
+            if state.parallel && children.len() < 2 {
+                return Err(BuildError::TooFewRegions {
+                    state: state.name.clone(),
+                    regions: children.len(),
+                });
+            }
         }
 
-        // Initialize current and previuos state to initial state
-        self.idx_current_state = idx_initial_state;
-        self.idx_previous_state = idx_initial_state;
+        // Validate the initial children, states that have
+        // one are transition targets
+        for idx in 0..self.states.len() {
+            if let Some(idx_child) = self.states[idx].initial_child {
+                if self.states[idx].parallel || !self.children[idx].contains(&idx_child) {
+                    return Err(BuildError::InvalidInitialChild {
+                        state: self.states[idx].name.clone(),
+                        idx_child,
+                    });
+                }
+                self.transition_targets_set[idx] = true;
+            }
+        }
 
-        // Initialize the idx_enter_fns array, start by
-        // always pushing the destination
-        let mut idx_enter = self.idx_current_state;
-        //log::trace!("initialialize: push idx_enter={} {}", idx_enter, self.state_name(idx_enter));
-        self.idxs_enter_fns.push(idx_enter);
+        // Validate the targets of the transition tables
+        for state in self.states.iter() {
+            for rule in state.transitions.iter() {
+                if let Some(idx_target) = rule.target {
+                    if !self.is_transition_target(idx_target) {
+                        return Err(BuildError::InvalidRuleTarget {
+                            state: state.name.clone(),
+                            label: rule.label.clone(),
+                            idx_target,
+                        });
+                    }
+                }
+            }
+        }
 
-        // Then push parents of the destination state so they are also entered.
-        while let Some(idx) = self.states[idx_enter].parent {
-            idx_enter = idx;
+        // Validate idx_initial_state is valid.
+        if idx_initial_state >= self.states.len() {
+            return Err(BuildError::InitialStateOutOfRange {
+                idx_initial: idx_initial_state,
+                len: self.states.len(),
+            });
+        }
+        if !self.transition_targets_set[idx_initial_state] {
+            return Err(BuildError::InitialStateNotTarget {
+                state: self.states[idx_initial_state].name.clone(),
+                targets: self.transition_target_names(),
+            });
+        }
 
-            //log::trace!("initialialize: push idx_enter={} {}", idx_enter, self.state_name(idx_enter));
-            self.idxs_enter_fns.push(idx_enter);
+        // Validate the error states are valid.
+        let error_states = [
+            match &self.invalid_transition_policy {
+                InvalidTransitionPolicy::ErrorState(error_state) => Some(error_state.idx()),
+                _ => None,
+            },
+            match &self.defer_overflow_policy {
+                DeferOverflowPolicy::ErrorState(error_state) => Some(error_state.idx()),
+                _ => None,
+            },
+        ];
+        for idx_error_state in error_states.into_iter().flatten() {
+            if !self.is_transition_target(idx_error_state) {
+                return Err(BuildError::InvalidErrorState {
+                    idx_error_state,
+                    targets: self.transition_target_names(),
+                });
+            }
         }
 
+        self.idx_initial_state = idx_initial_state;
+        self.setup_initial_enter_fns_idxs();
+
         Ok(self)
     }
 
+    // Make the initial state the current state and setup
+    // idxs_enter_fns so it, its parents and the regions of
+    // any parallel parents are entered.
+    fn setup_initial_enter_fns_idxs(&mut self) {
+        // Initialize current and previuos state to initial state
+        let (idx_initial, idx_deep) = self.resolve_target(self.idx_initial_state);
+        self.idx_current_state = idx_initial;
+        self.idx_previous_state = idx_initial;
+        self.current_state_changed = true;
+
+        self.idxs_enter_fns.clear();
+        let idx_root = self.idx_root(idx_initial);
+        self.push_enter_fns_idxs(idx_root, idx_initial, idx_deep);
+    }
+
     // Kahns algorithm for detecting cycles using a Breath First Search
     //   https://www.geeksforgeeks.org/detect-cycle-in-a-directed-graph-using-bfs/
-    fn cycle_detector(&mut self) -> bool {
+    //
+    // Returns the indexes of the states that were never visited,
+    // these are the states in a cycle. Empty if there is no cycle.
+    fn cycle_detector(&mut self) -> Vec<usize> {
         let mut leafs = self.transition_targets.to_vec();
         //println!("cycle_dector: leafs: {leafs:?}");
 
+        let mut visited = vec![false; self.states.len()];
         let mut visited_cnt = 0usize;
         while let Some(leaf_idx) = leafs.pop() {
+            visited[leaf_idx] = true;
             visited_cnt += 1;
             //println!("cycle_dector: leaf_idx={leaf_idx} visited_cnt={visited_cnt}");
 
@@ -242,7 +1253,29 @@ where
         }
         //println!("cycle_dector: visited_cnt: {visited_cnt} state.len()={}", self.states.len());
 
-        visited_cnt != self.states.len()
+        if visited_cnt == self.states.len() {
+            return Vec::new();
+        }
+        (0..self.states.len())
+            .filter(|idx| !visited[*idx])
+            .collect()
+    }
+
+    fn state_names(&self, idxs: &[usize]) -> Vec<String> {
+        idxs.iter()
+            .map(|idx| self.states[*idx].name.clone())
+            .collect()
+    }
+
+    // The names of the transition targets, excluding the history
+    // states as they're targets only within their parent
+    fn transition_target_names(&self) -> Vec<String> {
+        self.states
+            .iter()
+            .zip(self.transition_targets_set.iter())
+            .filter(|(state, target)| **target && state.history.is_none())
+            .map(|(state, _)| state.name.clone())
+            .collect()
     }
 
     // Determine Transition targets, (states with no children aka leafs)
@@ -265,173 +1298,917 @@ where
         }
     }
 
-    pub fn get_state_name(&self, idx: usize) -> &str {
-        &self.states[idx].name
+    pub fn get_state_name(&self, id: S) -> &str {
+        &self.states[id.idx()].name
     }
 
     pub fn get_current_state_name(&self) -> &str {
-        self.get_state_name(self.idx_current_state)
+        &self.states[self.idx_current_state].name
     }
 
-    pub fn get_sm(&self) -> &RefCell<SM> {
-        &self.sm
+    // The state hierarchy and the transitions of the transition
+    // tables, marking the initial and current states
+    pub fn diagram(&self) -> Diagram<'_> {
+        let mut diagram =
+            self.states
+                .iter()
+                .fold(Diagram::new(self.idx_initial_state), |diagram, state| {
+                    let diagram = if state.parallel {
+                        diagram.parallel_state(&state.name, state.parent)
+                    } else {
+                        diagram.state(&state.name, state.parent)
+                    };
+                    match state.initial_child {
+                        Some(idx_child) => diagram.initial_child(idx_child),
+                        None => diagram,
+                    }
+                });
+        for (idx, state) in self.states.iter().enumerate() {
+            for rule in state.transitions.iter() {
+                if let Some(idx_target) = rule.target {
+                    diagram = diagram.transition(idx, idx_target, &rule.label);
+                }
+            }
+        }
+
+        diagram.current(self.idx_current_state)
+    }
+
+    // Graphviz DOT
+    pub fn to_dot(&self) -> String {
+        self.diagram().to_dot()
+    }
+
+    // Mermaid stateDiagram-v2
+    pub fn to_mermaid(&self) -> String {
+        self.diagram().to_mermaid()
     }
 
-    pub fn get_state_enter_cnt(&self, idx: usize) -> usize {
-        self.states[idx].enter_cnt
+    pub fn to_plantuml(&self) -> String {
+        self.diagram().to_plantuml()
     }
-    pub fn get_state_process_cnt(&self, idx: usize) -> usize {
-        self.states[idx].process_cnt
+
+    pub fn get_sm(&self) -> &RefCell<SM> {
+        &self.sm
     }
 
-    pub fn get_state_exit_cnt(&self, idx: usize) -> usize {
-        self.states[idx].exit_cnt
+    pub fn get_state_enter_cnt(&self, id: S) -> usize {
+        self.states[id.idx()].enter_cnt
+    }
+    pub fn get_state_process_cnt(&self, id: S) -> usize {
+        self.states[id.idx()].process_cnt
     }
 
-    fn setup_exit_enter_fns_idxs(&mut self, idx_next_state: usize) {
-        let mut cur_idx = idx_next_state;
+    pub fn get_state_exit_cnt(&self, id: S) -> usize {
+        self.states[id.idx()].exit_cnt
+    }
 
-        // Setup the enter vector
+    // Setup the exits and enters of an external transition to
+    // idx_target whose leaf is idx_next_state. Returns the exit
+    // sentinel, the deepest active parent of idx_target, it's
+    // neither exited nor entered. None if the transition exits
+    // and enters up to the root. Below idx_deep states are
+    // entered at their last active child.
+    fn setup_exit_enter_fns_idxs(
+        &mut self,
+        idx_target: usize,
+        idx_next_state: usize,
+        idx_deep: Option<usize>,
+    ) -> Option<usize> {
+        // Find the exit sentinel and its child which is the first
+        // state entered. A history state is never active so the
+        // search starts at the leaf, its parent isn't exited if
+        // it's active.
+        let mut idx_enter = match self.states[idx_target].history {
+            Some(_) => idx_next_state,
+            None => idx_target,
+        };
         let exit_sentinel = loop {
-            //log::trace!("setup_exit_enter_fns_idxs: cur_idx={} {}, TOL", cur_idx, self.state_name(cur_idx));
-            self.idxs_enter_fns.push(cur_idx);
+            //log::trace!("setup_exit_enter_fns_idxs: idx_enter={} {}, TOL", idx_enter, self.state_name(idx_enter));
+            match self.states[idx_enter].parent {
+                Some(idx) if self.states[idx].active => break Some(idx),
+                Some(idx) => idx_enter = idx,
+                None => break None,
+            }
+        };
 
-            cur_idx = if let Some(idx) = self.states[cur_idx].parent {
-                idx
-            } else {
-                // Exit state_infos[self.current_state_infos_idx] and all its parents
-                //log::trace!("setup_exit_enter_fns_idxs: cur_idx={} {} has no parent exit_sentinel=None", cur_dx, self.state_name(cur_idx));
-                break None;
-            };
+        // Exit the active states below the exit sentinel, if it's a
+        // parallel state only the region being entered is exited.
+        // This always exits idx_target if it's active, i.e. a
+        // transition to the current state or one of its ancestors
+        // exits and enters it.
+        let idx_exit = match exit_sentinel {
+            Some(idx) if self.states[idx].parallel => idx_enter,
+            Some(idx) => self.idx_active_child(idx).unwrap_or(idx_enter),
+            None => self.idx_root(self.idx_current_state),
+        };
+        self.push_exit_fns_idxs(idx_exit);
+        self.push_enter_fns_idxs(idx_enter, idx_next_state, idx_deep);
 
-            if self.states[cur_idx].active {
-                // Exit state_infos[self.current_state_infos_idx] and
-                // parents upto but excluding state_infos[cur_idx]
-                //log::trace!("setup_exit_enter_fns_idxs: cur_idx={} {} is active so it's exit_sentinel", cur_idx, self.state_name(cur_idx));
-                break Some(cur_idx);
+        exit_sentinel
+    }
+
+    // The leaf a transition to idx enters, for a state with children
+    // found by following the initial children. For a deep history
+    // state its parent is returned too, the parent's descendants are
+    // entered at their last active child rather than their initial.
+    fn resolve_target(&self, idx: usize) -> (usize, Option<usize>) {
+        let Some(history) = self.states[idx].history else {
+            let mut idx = idx;
+            while let Some(idx_child) = self.idx_entry_child(idx, false) {
+                idx = idx_child;
             }
+
+            return (idx, None);
+        };
+        let idx_parent = self.states[idx]
+            .parent
+            .expect("SNH, build validated history");
+        let idx_deep = match history {
+            History::Shallow => None,
+            History::Deep => Some(idx_parent),
         };
 
-        // Starting at self.idx_current_state generate the
-        // list of StateFns that we're going to exit. If exit_sentinel is None
-        // then exit from idx_current_state and all of its parents.
-        // If exit_sentinel is Some then exit from the idx_current_state
-        // up to but not including the exit_sentinel.
-        let mut idx_exit = self.idx_current_state;
+        let mut idx = idx_parent;
+        let mut last_active = true;
+        while let Some(idx_child) = self.idx_entry_child(idx, last_active) {
+            idx = idx_child;
+            last_active = idx_deep.is_some();
+        }
+
+        (idx, idx_deep)
+    }
+
+    // The child entered when entering idx without a target, if
+    // last_active is true the active child or the last active child,
+    // otherwise or if there is none the initial child.
+    fn idx_entry_child(&self, idx: usize, last_active: bool) -> Option<usize> {
+        let idx_last_active = if last_active && !self.states[idx].parallel {
+            self.idx_active_child(idx)
+                .or(self.states[idx].last_active_child)
+        } else {
+            None
+        };
+
+        idx_last_active
+            .or(self.states[idx].initial_child)
+            .or_else(|| self.children[idx].first().copied())
+    }
+
+    // Push the active states of the tree at idx onto idxs_exit_fns,
+    // children before parents and the regions of a parallel state
+    // in the reverse order they were added.
+    fn push_exit_fns_idxs(&mut self, idx: usize) {
+        if !self.states[idx].active {
+            return;
+        }
+
+        if self.states[idx].parallel {
+            for i in (0..self.children[idx].len()).rev() {
+                self.push_exit_fns_idxs(self.children[idx][i]);
+            }
+        } else if let Some(idx_child) = self.idx_active_child(idx) {
+            self.push_exit_fns_idxs(idx_child);
+        }
+        if let Some(idx_parent) = self.states[idx].parent {
+            self.states[idx_parent].last_active_child = Some(idx);
+        }
+        //log::trace!( "push_exit_fns_idxs: push_back(idx={} {})", idx, self.state_name(idx));
+        self.idxs_exit_fns.push_back(idx);
+    }
+
+    // Push the states entered when entering idx to reach the leaf
+    // idx_target onto idxs_enter_fns. It's a stack so the last
+    // state entered is pushed first.
+    fn push_enter_fns_idxs(&mut self, idx: usize, idx_target: usize, idx_deep: Option<usize>) {
+        let mut idxs = Vec::new();
+        self.enter_idxs(idx, Some(idx_target), idx_deep, &mut idxs);
+        self.idxs_enter_fns.extend(idxs.iter().rev());
+    }
+
+    // Append the states entered when entering idx, in the order they are
+    // entered, to idxs. A child that isn't towards idx_target is entered
+    // at its initial child, or below idx_deep its last active child.
+    fn enter_idxs(
+        &self,
+        idx: usize,
+        idx_target: Option<usize>,
+        idx_deep: Option<usize>,
+        idxs: &mut Vec<usize>,
+    ) {
+        idxs.push(idx);
+
+        let idx_target = idx_target.filter(|idx_target| *idx_target != idx);
+        if self.states[idx].parallel {
+            for idx_region in self.children[idx].iter() {
+                let idx_target = idx_target.filter(|t| self.is_descendant(*t, *idx_region));
+                self.enter_idxs(*idx_region, idx_target, idx_deep, idxs);
+            }
+        } else {
+            let idx_child = match idx_target {
+                Some(idx_target) => self.idx_child_towards(idx, idx_target),
+                None => {
+                    let deep = idx_deep.is_some_and(|idx_deep| self.is_descendant(idx, idx_deep));
+                    self.idx_entry_child(idx, deep)
+                }
+            };
+            if let Some(idx_child) = idx_child {
+                self.enter_idxs(idx_child, idx_target, idx_deep, idxs);
+            }
+        }
+    }
+
+    fn idx_root(&self, mut idx: usize) -> usize {
+        while let Some(idx_parent) = self.states[idx].parent {
+            idx = idx_parent;
+        }
+
+        idx
+    }
+
+    // The active child of a state that isn't parallel
+    fn idx_active_child(&self, idx: usize) -> Option<usize> {
+        self.children[idx]
+            .iter()
+            .copied()
+            .find(|idx_child| self.states[*idx_child].active)
+    }
 
-        // Always exit the first state, this handles the special case
-        // where Some(idx_exit) == exit_sentinel and we need to exit anyway.
-        //log::trace!("setup_exit_enter_fns_idxs: push_back(idx_exit={} {})", idx_exit, self.state_name(idx_exit));
-        self.idxs_exit_fns.push_back(idx_exit);
+    // The child of idx that is idx_target or one of its parents
+    fn idx_child_towards(&self, idx: usize, mut idx_target: usize) -> Option<usize> {
+        while let Some(idx_parent) = self.states[idx_target].parent {
+            if idx_parent == idx {
+                return Some(idx_target);
+            }
+            idx_target = idx_parent;
+        }
 
-        while let Some(idx) = self.states[idx_exit].parent {
-            idx_exit = idx;
+        None
+    }
 
-            if Some(idx_exit) == exit_sentinel {
-                // Reached the exit sentinel so we're done
-                //log::trace!("setup_exit_enter_fns_idxs: idx_exit={} {} == exit_sentinel={} {}, reached exit_sentinel return", idx_exit, self.state_name(idx_exit), exit_sentinel.unwrap(), self.state_name(exit_sentinel.unwrap()));
-                return;
+    // Returns true if idx is idx_ancestor or one of its descendants
+    fn is_descendant(&self, mut idx: usize, idx_ancestor: usize) -> bool {
+        loop {
+            if idx == idx_ancestor {
+                return true;
             }
+            match self.states[idx].parent {
+                Some(idx_parent) => idx = idx_parent,
+                None => return false,
+            }
+        }
+    }
+
+    // The names of the active leafs, one for each region of the
+    // active parallel states, in the order the states were added.
+    pub fn get_active_leaf_names(&self) -> Vec<&str> {
+        self.transition_targets
+            .iter()
+            .filter(|idx| self.states[**idx].active)
+            .map(|idx| self.states[*idx].name.as_str())
+            .collect()
+    }
+
+    // Dispatch msg to idx and its parents, ignoring any regions
+    pub fn dispatch_idx(&mut self, msg: &P, idx: usize) -> Result<(), DispatchError> {
+        let info = self.primary_tx.stamp();
+        self.dispatch_msg(&mut MsgRef::Ref(msg), Some(idx), info)?;
+
+        Ok(())
+    }
 
-            //log::trace!( "setup_exit_enter_fns_idxs: push_back(idx_exit={} {})", idx_exit, self.state_name(idx_exit));
-            self.idxs_exit_fns.push_back(idx_exit);
+    // Process msg starting at idx, or if None at the active leafs, then
+    // make the first transition returned by a process fn. Returns true
+    // if a transition, other than an internal one, was made.
+    fn dispatch_msg_idx(
+        &mut self,
+        msg: &mut MsgRef<'_, P>,
+        idx: Option<usize>,
+    ) -> Result<bool, DispatchError> {
+        //log::trace!("dispatch_idx:+ idx={:?}", idx);
+
+        let mut transitioned = self.enter_and_complete(msg.get())?;
+
+        let handled = match idx {
+            Some(idx) => self.process_up(msg, idx)?,
+            None if self.defer_declared(msg.get()) => Handled::Yes,
+            None => self.process_active(msg, self.idx_root(self.idx_current_state))?,
+        };
+        if let Handled::No = handled {
+            self.unhandled(msg.get());
+        }
+        if self.defer_overflowed.take() {
+            self.defer_overflow_transition();
+        }
+        transitioned |= self.make_transition(msg.get())?;
+
+        // A final state is entered now so the completion and
+        // termination are seen by the dispatch reaching it
+        if self.current_state_changed && self.states[self.idx_current_state].is_final {
+            transitioned |= self.enter_and_complete(msg.get())?;
         }
+
+        //log::trace!("dispatch_idx:- idx={:?}", idx);
+        Ok(transitioned)
     }
 
-    pub fn dispatch_idx(&mut self, msg: &P, idx: usize) {
-        //log::trace!("dispatch_idx:+ idx={} {}", idx, self.state_name(idx));
+    // Make the first transition returned while dispatching msg, then
+    // exit the states and run the transition action. Returns true if
+    // the current state changed, an internal transition doesn't.
+    fn make_transition(&mut self, msg: &P) -> Result<bool, DispatchError> {
+        let mut transition_action = None;
+        if let Some(idx_next_state) = self.idx_transition_dest {
+            self.idx_transition_dest = None;
+            let action = self.transition_dest_action.take();
+
+            // Any active state is a valid target of an internal transition
+            let internal = self.transition_dest_kind == TransitionKind::Internal
+                && idx_next_state < self.states.len()
+                && self.states[idx_next_state].active;
+            let idx_next_state = if internal || self.is_transition_target(idx_next_state) {
+                // The action only runs if the transition is made as returned
+                transition_action = action;
+                Some(idx_next_state)
+            } else {
+                self.invalid_transition(msg, idx_next_state)?
+            };
+            if let Some(idx_target) = idx_next_state {
+                //log::trace!("dispatch_idx: transition_to idx={} {}", idx_target, self.state_name(idx_target));
+                // An internal transition to an active state exits and enters
+                // nothing, the target is its own exit sentinel
+                let (idx_next_state, idx_ancestor) = if internal {
+                    (idx_target, Some(idx_target))
+                } else {
+                    let (idx_next_state, idx_deep) = self.resolve_target(idx_target);
+                    let idx_ancestor =
+                        self.setup_exit_enter_fns_idxs(idx_target, idx_next_state, idx_deep);
+                    (idx_next_state, idx_ancestor)
+                };
+                self.notify(|observer| {
+                    observer.on_transition(
+                        self.state_ref(self.idx_transition_src),
+                        self.state_ref(idx_next_state),
+                        idx_ancestor.map(|idx| self.state_ref(idx)),
+                        msg,
+                    )
+                });
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder.transition(idx_next_state);
+                }
+
+                if !internal {
+                    self.idx_previous_state = self.idx_current_state;
+                    self.idx_current_state = idx_next_state;
+                    self.current_state_changed = true;
+                }
+            }
+        }
 
         if self.current_state_changed {
-            // Execute the enter functions
-            while let Some(idx_enter) = self.idxs_enter_fns.pop() {
-                if let Some(state_enter) = self.states[idx_enter].enter {
-                    //log::trace!("dispatch_idx: entering idx={} {}", idx_enter, self.state_name(idx_enter));
-                    self.states[idx_enter].enter_cnt += 1;
-                    (state_enter)(&mut self.sm.borrow_mut(), msg);
-                    self.states[idx_enter].active = true;
+            self.exit_states(msg);
+        }
+        if let Some(action) = transition_action {
+            (action)(&mut self.sm.borrow_mut(), self, msg);
+        }
+
+        Ok(self.current_state_changed)
+    }
+
+    // Enter the pending states and raise the completion events of the
+    // states completed by the final states entered. The transition a
+    // completion fn returns is made and its states entered, until no
+    // transition is returned. Returns true if a transition was made.
+    fn enter_and_complete(&mut self, msg: &P) -> Result<bool, DispatchError> {
+        let mut transitioned = false;
+        loop {
+            for idx_final in self.enter_states(msg) {
+                self.raise_completions(msg, idx_final);
+            }
+            if !self.make_transition(msg)? {
+                return Ok(transitioned);
+            }
+            transitioned = true;
+        }
+    }
+
+    // Raise the completion events of the parents of idx_final that
+    // are completed, see StateInfo::completion_fn
+    fn raise_completions(&mut self, msg: &P, idx_final: usize) {
+        let mut idx = idx_final;
+        while let Some(idx_parent) = self.states[idx].parent {
+            if !self.is_complete(idx_parent) {
+                break;
+            }
+            if let Some(completion_fn) = self.states[idx_parent].completion {
+                self.transition_action.set(None);
+                self.transition_kind.set(TransitionKind::External);
+                self.idx_running_state.set(Some(idx_parent));
+                let (_, transition) = (completion_fn)(&mut self.sm.borrow_mut(), self, msg);
+                self.idx_running_state.set(None);
+                if let Some(next_state) = transition {
+                    self.save_transition(idx_parent, next_state.idx());
                 }
             }
-            self.current_state_changed = false;
+            self.notify(|observer| observer.on_completion(self.state_ref(idx_parent), msg));
+            idx = idx_parent;
+        }
+    }
+
+    // Returns true if the active child of idx, or of each of its
+    // regions if it's parallel, is a final state
+    fn is_complete(&self, idx: usize) -> bool {
+        if self.states[idx].parallel {
+            self.children[idx]
+                .iter()
+                .all(|idx_region| self.is_complete(*idx_region))
+        } else {
+            self.idx_active_child(idx)
+                .is_some_and(|idx_child| self.states[idx_child].is_final)
         }
+    }
 
-        // Invoke the current state funtion processing the result
-        //log::trace!("dispatch_idx: processing idx={} {}", idx, self.state_name(idx));
+    // Running until the active leafs are all final states
+    pub fn status(&self) -> Status {
+        let mut idxs_active = self
+            .transition_targets
+            .iter()
+            .filter(|idx| self.states[**idx].active)
+            .peekable();
+        if !self.current_state_changed
+            && idxs_active.peek().is_some()
+            && idxs_active.all(|idx| self.states[*idx].is_final)
+        {
+            Status::Terminated
+        } else {
+            Status::Running
+        }
+    }
 
-        self.states[idx].process_cnt += 1;
-        let (handled, transition) =
-            (self.states[idx].process)(&mut self.sm.borrow_mut(), self, msg);
-        if let Some(idx_next_state) = transition {
-            if self.idx_transition_dest.is_none() {
-                // First Transition it will be the idx_transition_dest
-                self.idx_transition_dest = Some(idx_next_state);
+    // Process msg with idx and, while it's not handled, its parents
+    fn process_up(
+        &mut self,
+        msg: &mut MsgRef<'_, P>,
+        idx: usize,
+    ) -> Result<Handled, DispatchError> {
+        let mut idx = idx;
+        loop {
+            if let Handled::Yes = self.process(msg, idx)? {
+                return Ok(Handled::Yes);
+            }
+            match self.states[idx].parent {
+                Some(idx_parent) => {
+                    //log::trace!("process_up: idx={} {} NotHandled, try parent", idx, self.state_name(idx));
+                    idx = idx_parent;
+                }
+                None => return Ok(Handled::No),
             }
         }
-        match handled {
-            Handled::No => {
-                if let Some(idx_parent) = self.states[idx].parent {
-                    //log::trace!("dispatch_idx: idx={} {} NotHandled, recurse into dispatch_idx", idx, self.state_name(idx));
-                    self.dispatch_idx(msg, idx_parent);
+    }
+
+    // Process msg with the active leafs below idx and their parents up
+    // to idx. Every region of a parallel state is offered msg, and the
+    // parallel state only processes it if none of them handled it.
+    fn process_active(
+        &mut self,
+        msg: &mut MsgRef<'_, P>,
+        idx: usize,
+    ) -> Result<Handled, DispatchError> {
+        let mut handled = Handled::No;
+        if self.states[idx].parallel {
+            for i in 0..self.children[idx].len() {
+                if let Handled::Yes = self.process_active(msg, self.children[idx][i])? {
+                    handled = Handled::Yes;
                 }
-                //} else {
-                //    log::trace!("dispatch_idx: idx={} {}, NotHandled, no parent, ignoring messages", idx, self.state_name(idx));
-                //}
             }
-            Handled::Yes => {
-                // Nothing to do
-                //log::trace!("dispatch_idx: idx={} {} Handled", idx, self.state_name(idx));
+        } else if let Some(idx_child) = self.idx_active_child(idx) {
+            handled = self.process_active(msg, idx_child)?;
+        }
+
+        match handled {
+            Handled::Yes => Ok(Handled::Yes),
+            Handled::No => self.process(msg, idx),
+        }
+    }
+
+    // Invoke the process fn of idx, the first transition returned
+    // while dispatching a msg is saved in idx_transition_dest.
+    fn process(&mut self, msg: &mut MsgRef<'_, P>, idx: usize) -> Result<Handled, DispatchError> {
+        //log::trace!("process: idx={} {}", idx, self.state_name(idx));
+        let process = self.states[idx].process;
+        if let (Process::Mut(_), MsgRef::Ref(_)) = (process, &msg) {
+            return Err(DispatchError::MutProcessWithRef {
+                idx,
+                state: self.states[idx].name.clone(),
+            });
+        }
+        self.states[idx].process_cnt += 1;
+        self.transition_action.set(None);
+        self.transition_kind.set(TransitionKind::External);
+        let idx_running_state = self.idx_running_state.replace(Some(idx));
+        let (handled, transition) = match self.idx_matching_rule(idx, msg.get()) {
+            Some(idx_rule) => self.apply_rule(idx, idx_rule, msg.get()),
+            None => {
+                let (handled, transition) = match (process, &mut *msg) {
+                    (Process::Mut(process_fn), MsgRef::Mut(msg)) => {
+                        (process_fn)(&mut self.sm.borrow_mut(), self, msg)
+                    }
+                    (Process::Ref(process_fn), msg) => {
+                        (process_fn)(&mut self.sm.borrow_mut(), self, msg.get())
+                    }
+                    (Process::Mut(_), MsgRef::Ref(_)) => unreachable!(),
+                };
+                (handled, transition.map(|next_state| next_state.idx()))
             }
+        };
+        self.idx_running_state.set(idx_running_state);
+        self.notify(|observer| {
+            observer.on_process(
+                self.state_ref(idx),
+                msg.get(),
+                matches!(handled, Handled::Yes),
+            )
+        });
+        if let Some(idx_next_state) = transition {
+            self.save_transition(idx, idx_next_state);
         }
 
-        if let Some(idx_next_state) = self.idx_transition_dest {
-            self.idx_transition_dest = None;
-            if idx_next_state < self.states.len() && self.transition_targets_set[idx_next_state] {
-                //log::trace!("dispatch_idx: transition_to idx={} {}", idx_next_state, self.state_name(idx_next_state));
-                self.setup_exit_enter_fns_idxs(idx_next_state);
+        Ok(handled)
+    }
 
-                self.idx_previous_state = self.idx_current_state;
-                self.idx_current_state = idx_next_state;
-                self.current_state_changed = true;
-            } else {
-                panic!(
-                    "{idx_next_state} is not a valid transition target, only {:?} are allowed",
-                    self.transition_targets
-                );
+    // Save the transition idx returned, with the action and kind it
+    // set, if it's the first transition returned while dispatching
+    fn save_transition(&mut self, idx: usize, idx_next_state: usize) {
+        if self.idx_transition_dest.is_none() {
+            // First Transition it will be the idx_transition_dest
+            self.idx_transition_dest = Some(idx_next_state);
+            self.idx_transition_src = idx;
+            self.transition_dest_action = self.transition_action.take();
+            self.transition_dest_kind = self.transition_kind.get();
+        }
+    }
+
+    // The first rule in the transition table of idx matching msg
+    fn idx_matching_rule(&self, idx: usize, msg: &P) -> Option<usize> {
+        let sm = self.sm.borrow();
+        self.states[idx]
+            .transitions
+            .iter()
+            .position(|rule| (rule.event)(msg) && rule.guard.is_none_or(|guard| guard(&sm, msg)))
+    }
+
+    // Handle msg with a rule, its action is the transition action
+    // or if there is no target it's run now
+    fn apply_rule(&self, idx: usize, idx_rule: usize, msg: &P) -> (Handled, Option<usize>) {
+        let rule = &self.states[idx].transitions[idx_rule];
+        match (rule.target, rule.action) {
+            (Some(_), action) => {
+                self.transition_action.set(action);
+                self.transition_kind.set(rule.kind);
             }
+            (None, Some(action)) => (action)(&mut self.sm.borrow_mut(), self, msg),
+            (None, None) => (),
         }
 
+        (Handled::Yes, rule.target)
+    }
+
+    // Execute the enter functions of the states that are pending
+    // entry, returns the final states entered
+    fn enter_states(&mut self, msg: &P) -> Vec<usize> {
+        let mut idxs_final = Vec::new();
         if self.current_state_changed {
-            while let Some(idx_exit) = self.idxs_exit_fns.pop_front() {
-                if let Some(state_exit) = self.states[idx_exit].exit {
-                    //log::trace!("dispatch_idx: exiting idx={} {}", idx_exit, self.state_name(idx_exit));
-                    self.states[idx_exit].exit_cnt += 1;
-                    (state_exit)(&mut self.sm.borrow_mut(), msg);
-                    self.states[idx_exit].active = false;
+            while let Some(idx_enter) = self.idxs_enter_fns.pop() {
+                if let Some(state_enter) = self.states[idx_enter].enter {
+                    //log::trace!("enter_states: entering idx={} {}", idx_enter, self.state_name(idx_enter));
+                    self.states[idx_enter].enter_cnt += 1;
+                    self.idx_running_state.set(Some(idx_enter));
+                    (state_enter)(&mut self.sm.borrow_mut(), self, msg);
+                    self.idx_running_state.set(None);
                 }
+                self.states[idx_enter].active = true;
+                self.notify(|observer| observer.on_enter(self.state_ref(idx_enter), msg));
+                if self.states[idx_enter].is_final {
+                    idxs_final.push(idx_enter);
+                }
+            }
+            self.current_state_changed = false;
+        }
+
+        idxs_final
+    }
+
+    // Execute the exit functions of the states in idxs_exit_fns
+    fn exit_states(&mut self, msg: &P) {
+        while let Some(idx_exit) = self.idxs_exit_fns.pop_front() {
+            if let Some(state_exit) = self.states[idx_exit].exit {
+                //log::trace!("exit_states: exiting idx={} {}", idx_exit, self.state_name(idx_exit));
+                self.states[idx_exit].exit_cnt += 1;
+                (state_exit)(&mut self.sm.borrow_mut(), self, msg);
+            }
+            self.states[idx_exit].active = false;
+            self.notify(|observer| observer.on_exit(self.state_ref(idx_exit), msg));
+            self.timers.borrow_mut().cancel_owned_by(idx_exit);
+        }
+    }
+
+    // Enter the initial state and its parents now rather
+    // than on the first dispatch, msg is passed to the
+    // enter functions. Does nothing if already started.
+    pub fn start_with(&mut self, msg: &P) {
+        self.enter_states(msg);
+    }
+
+    // Same as start_with using P::default() as the msg
+    pub fn start(&mut self)
+    where
+        P: Default,
+    {
+        self.start_with(&P::default());
+    }
+
+    // Exit the active states, children before their parents,
+    // msg is passed to the exit functions.
+    //
+    // The executor is left as if it was just built so it can
+    // be started again. Deferred and queued messages remain.
+    pub fn stop_with(&mut self, msg: &P) {
+        self.idxs_exit_fns.clear();
+        self.push_exit_fns_idxs(self.idx_root(self.idx_current_state));
+        self.exit_states(msg);
+
+        self.idx_transition_dest = None;
+        self.transition_dest_action = None;
+        self.setup_initial_enter_fns_idxs();
+    }
+
+    // Same as stop_with using P::default() as the msg
+    pub fn stop(&mut self)
+    where
+        P: Default,
+    {
+        self.stop_with(&P::default());
+    }
+
+    // Returns a guard which derefs to this executor and
+    // calls stop_with(&msg) when it's dropped.
+    pub fn stop_on_drop(&mut self, msg: P) -> StopOnDrop<'_, SM, P, S> {
+        StopOnDrop {
+            executor: self,
+            msg: Some(msg),
+        }
+    }
+
+    // Add an observer, they're notified in the order added
+    pub fn observer(self, observer: impl Observer<P> + Send + 'static) -> Self {
+        self.observers.borrow_mut().push(Box::new(observer));
+
+        self
+    }
+
+    fn notify<F>(&self, mut f: F)
+    where
+        F: FnMut(&mut dyn Observer<P>),
+    {
+        for observer in self.observers.borrow_mut().iter_mut() {
+            f(observer.as_mut());
+        }
+    }
+
+    // Unhandled message support
+
+    // Count msg, invoke the unhandled fn and keep msg as a DeadLetter
+    fn unhandled(&mut self, msg: &P) {
+        self.unhandled_cnt += 1;
+        self.notify(|observer| observer.on_unhandled(self.state_ref(self.idx_current_state), msg));
+        if let Some(unhandled_fn) = self.unhandled_fn {
+            (unhandled_fn)(&mut self.sm.borrow_mut(), self, msg);
+        }
+        if self.dead_letter_capacity > 0 {
+            if self.dead_letters.len() == self.dead_letter_capacity {
+                self.dead_letters.pop_front();
+                self.dead_letters_dropped += 1;
+            }
+            self.dead_letters.push_back(DeadLetter {
+                idx_state: self.idx_current_state,
+                state: self.states[self.idx_current_state].name.clone(),
+                msg: format!("{msg:?}"),
+            });
+        }
+    }
+
+    // The number of messages no state handled
+    pub fn unhandled_cnt(&self) -> u64 {
+        self.unhandled_cnt
+    }
+
+    // The number of DeadLetters dropped because there were
+    // already dead_letter_capacity of them
+    pub fn dead_letters_dropped(&self) -> u64 {
+        self.dead_letters_dropped
+    }
+
+    // Remove and return the DeadLetters, oldest first
+    pub fn take_dead_letters(&mut self) -> Vec<DeadLetter> {
+        self.dead_letters.drain(..).collect()
+    }
+
+    fn state_ref(&self, idx: usize) -> StateRef<'_> {
+        StateRef {
+            idx,
+            name: &self.states[idx].name,
+        }
+    }
+
+    fn is_transition_target(&self, idx: usize) -> bool {
+        idx < self.states.len() && self.transition_targets_set[idx]
+    }
+
+    // Apply the invalid_transition_policy, returns the state
+    // to transition to, if any.
+    fn invalid_transition(
+        &mut self,
+        msg: &P,
+        idx_dest: usize,
+    ) -> Result<Option<usize>, DispatchError> {
+        let idx_src = self.idx_transition_src;
+        let invalid = InvalidTransition {
+            idx_src,
+            src: self.states[idx_src].name.clone(),
+            idx_dest,
+            msg: format!("{msg:?}"),
+        };
+
+        match &self.invalid_transition_policy {
+            InvalidTransitionPolicy::Panic => panic!(
+                "{idx_dest} is not a valid transition target, only {:?} are allowed",
+                self.transition_targets
+            ),
+            InvalidTransitionPolicy::ReturnError => Err(DispatchError::InvalidTransition(invalid)),
+            InvalidTransitionPolicy::ErrorState(error_state) => Ok(Some(error_state.idx())),
+            InvalidTransitionPolicy::Hook(hook) => {
+                (hook)(&mut self.sm.borrow_mut(), self, &invalid, msg);
+                Ok(None)
             }
         }
+    }
+
+    pub fn dispatch(&mut self, msg: &P) -> Result<bool, DispatchError> {
+        let info = self.primary_tx.stamp();
+        self.dispatch_msg(&mut MsgRef::Ref(msg), None, info)
+    }
 
-        //log::trace!("dispatch_idx:- idx={} {}", idx, self.state_name(idx));
+    // Dispatch a message that process fns created with
+    // StateInfo::new_mut may modify or take the payload of.
+    pub fn dispatch_mut(&mut self, msg: &mut P) -> Result<bool, DispatchError> {
+        let info = self.primary_tx.stamp();
+        self.dispatch_msg(&mut MsgRef::Mut(msg), None, info)
     }
 
-    pub fn dispatch(&mut self, msg: &P) -> bool {
+    fn dispatch_msg(
+        &mut self,
+        msg: &mut MsgRef<'_, P>,
+        idx: Option<usize>,
+        info: MsgInfo,
+    ) -> Result<bool, DispatchError> {
         //log::trace!( "dispatch:+ current_state_infos_idx={} {}", self.idx_current_state, self.current_state_name());
-        self.dispatch_idx(msg, self.idx_current_state);
+        // Ignore an overflow from a defer_send outside of a dispatch
+        self.defer_overflowed.set(false);
+        self.current_msg_info.set(Some(info));
+        self.notify(|observer| observer.on_dispatch(info, msg.get()));
+        let transitioned = self.dispatch_msg_idx(msg, idx);
+        self.current_msg_info.set(None);
         //log::trace!( "dispatch:- current_state_infos_idx={} {}", self.idx_current_state, self.current_state_name());
 
-        self.current_state_changed
+        transitioned
+    }
+
+    // The info of the message being dispatched, None outside of
+    // a dispatch. A replayed deferred message has the info it was
+    // first accepted with.
+    pub fn current_msg_info(&self) -> Option<MsgInfo> {
+        self.current_msg_info.get()
     }
 
     // TODO: More testing at warnings are needed that defering messages
     // is "dangerous" and processing time increases for new messages. There
-    // maybe other dangers too!
-    pub fn dispatcher(&mut self, msg: &P) {
+    // maybe other dangers too! The number of deferred messages and how
+    // long they're kept can be limited with defer_capacity,
+    // defer_max_replays and defer_max_age.
+    //
+    // An error from dispatching a deferred message is returned
+    // after the rest of the deferred messages it was replayed with
+    // are, messages deferred meanwhile are processed after a
+    // subsequent transition.
+    //
+    // Returns Status::Terminated once the machine reaches its final
+    // states, see StateInfo::new_final.
+    pub fn dispatcher(&mut self, msg: &P) -> Result<Status, DispatchError> {
+        let info = self.primary_tx.stamp();
+        self.dispatcher_msg(&mut MsgRef::Ref(msg), info)
+    }
+
+    // Same as dispatcher but msg may be modified, see dispatch_mut
+    pub fn dispatcher_mut(&mut self, msg: &mut P) -> Result<Status, DispatchError> {
+        let info = self.primary_tx.stamp();
+        self.dispatcher_msg(&mut MsgRef::Mut(msg), info)
+    }
+
+    // Same as dispatcher_mut for a message received with its info
+    // by recv_with_info or try_recv_with_info, msg keeps the info
+    // it was sent with instead of being stamped again
+    pub fn dispatcher_with_info(
+        &mut self,
+        msg: &mut P,
+        info: MsgInfo,
+    ) -> Result<Status, DispatchError> {
+        self.dispatcher_msg(&mut MsgRef::Mut(msg), info)
+    }
+
+    // Same as dispatcher_mut but takes ownership of msg
+    pub fn dispatcher_owned(&mut self, mut msg: P) -> Result<Status, DispatchError> {
+        self.dispatcher_mut(&mut msg)
+    }
+
+    fn dispatcher_msg(
+        &mut self,
+        msg: &mut MsgRef<'_, P>,
+        info: MsgInfo,
+    ) -> Result<Status, DispatchError> {
+        self.dispatch_recorded(msg, info)?;
+        self.drain()?;
+
+        Ok(self.status())
+    }
+
+    // The message is recorded even if dispatching it fails
+    fn dispatch_recorded(
+        &mut self,
+        msg: &mut MsgRef<'_, P>,
+        info: MsgInfo,
+    ) -> Result<(), DispatchError> {
+        let encoded = self.recorder.as_mut().map(|r| r.begin(msg.get()));
+        let result = self.dispatch_with_deferred(msg, info);
+        let recorded = encoded.map(|encoded| self.record_dispatched(encoded, info));
+        result?;
+        recorded.unwrap_or(Ok(()))
+    }
+
+    // Dispatch the messages in the primary queue, see drain_budget
+    fn drain(&mut self) -> Result<(), DispatchError> {
+        let Some(budget) = self.drain_budget else {
+            return Ok(());
+        };
+        let mut drained = 0;
+        // The messages after a stop are left for after run returns
+        while self.status() == Status::Running && !self.stop_received.get() {
+            if drained == budget {
+                self.drain_exhausted += 1;
+                break;
+            }
+            self.send_expired_timers();
+            match self.primary_rx.try_recv() {
+                Ok(Envelope::Timer(id, ..)) if !self.timer_received(id) => {}
+                Ok(Envelope::Msg(mut msg, info) | Envelope::Timer(_, mut msg, info)) => {
+                    self.dispatch_recorded(&mut MsgRef::Mut(&mut msg), info)?;
+                    drained += 1;
+                }
+                Ok(Envelope::Stop) => {
+                    // A stop was drained, rearm it so the next
+                    // recv or try_recv returns Err and run returns
+                    self.stop_received.set(true);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => unreachable!("SNH, we own primary_tx"),
+            }
+        }
+
+        Ok(())
+    }
+
+    // The number of times draining stopped because drain_budget
+    // messages were dispatched
+    pub fn drain_exhausted(&self) -> u64 {
+        self.drain_exhausted
+    }
+
+    // Deferred messages are owned by the executor so they are
+    // always dispatched with dispatch_mut.
+    fn dispatch_with_deferred(
+        &mut self,
+        msg: &mut MsgRef<'_, P>,
+        info: MsgInfo,
+    ) -> Result<(), DispatchError> {
         //log::trace!("dispatcher:+ msg={msg:?} sm={:?}", self.get_sm());
-        let mut transitioned = self.dispatch(msg);
+        let mut transitioned = self.dispatch_msg(msg, None, info)?;
         //log::trace!("dispatcher:  msg={msg:?} sm={:?} ret={transitioned}", self.get_sm());
 
+        // The first error from a replayed deferred message, the rest
+        // of its queue is still replayed so none are left behind
+        let mut first_err = None;
+
         // Process all deferred messages we if we've transitioned
         // above or within the loop below.
-        while transitioned {
+        while transitioned && first_err.is_none() {
             //log::trace!("dispatcher:  TOL transitioned");
             transitioned = false;
 
@@ -442,11 +2219,32 @@ where
             // If we didn't do this we could process newly deferred message
             // before we process previously deferred messages. In other words,
             // we guarantee that previously sent messages are always processed
-            // before newly sent messages! TODO: add a messge counter or
-            // timestamp so we can guarantee this when testing!
-            while let Ok(m) = self.defer_try_recv() {
+            // before newly sent messages, as they keep their MsgInfo::seq
+            // this is checked by test_deferred_order.
+            while let Some(deferred) = self.defer_pop() {
+                if self.is_expired(&deferred) {
+                    self.defer_expired += 1;
+                    self.notify(|observer| observer.on_defer_expired(&deferred.msg));
+                    continue;
+                }
+                if self.deferred_by(&deferred.msg).is_some() {
+                    // Still deferred by the new configuration, keep it
+                    let current = self.current_defer();
+                    self.deferred.borrow_mut()[current].push_back(deferred);
+                    continue;
+                }
+                let mut m = deferred.msg;
                 //log::trace!("dispatcher:  deferred msg={m:?} sm={:?}", self.get_sm());
-                transitioned |= self.dispatch(&m);
+                self.notify(|observer| observer.on_deferred_replay(&m));
+                self.replaying.set(Some(deferred.replays + 1));
+                let result = self.dispatch_msg(&mut MsgRef::Mut(&mut m), None, deferred.info);
+                self.replaying.set(None);
+                match result {
+                    Ok(t) => transitioned |= t,
+                    Err(e) => {
+                        first_err.get_or_insert(e);
+                    }
+                }
                 //log::trace!("dispatcher:  deferred msg={m:?} sm={:?} ret={transitioned}", self.get_sm());
             }
         }
@@ -460,55 +2258,439 @@ where
         // called with a new message which causes a transition.
 
         //log::trace!("dispatcher:- msg={msg:?} sm={:?}", self.get_sm());
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
     // Defer support
+
+    // Receive the next message, waiting until one is sent or a
+    // timer expires. With a ManualClock expired timers are only
+    // noticed when a message arrives, use try_recv instead.
+    //
+    // Returns Err if a stop was requested, see MsgSender::stop.
+    // The MsgInfo msg was sent with is dropped so a dispatcher
+    // stamps it again, use recv_with_info to keep it.
     pub fn recv(&self) -> Result<P, RecvError> {
-        self.primary_rx.recv()
+        self.recv_with_info().map(|(msg, _)| msg)
+    }
+
+    // Same as recv but also returns the info msg was sent with
+    pub fn recv_with_info(&self) -> Result<(P, MsgInfo), RecvError> {
+        if self.stop_received.take() {
+            return Err(RecvError);
+        }
+        loop {
+            self.send_expired_timers();
+            let envelope = match self.next_timer_deadline() {
+                None => self.primary_rx.recv()?,
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(self.clock.now());
+                    match self.primary_rx.recv_timeout(timeout) {
+                        Ok(envelope) => envelope,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => return Err(RecvError),
+                    }
+                }
+            };
+            return match envelope {
+                Envelope::Timer(id, ..) if !self.timer_received(id) => continue,
+                Envelope::Msg(msg, info) | Envelope::Timer(_, msg, info) => Ok((msg, info)),
+                Envelope::Stop => Err(RecvError),
+            };
+        }
     }
 
+    // Returns Err(TryRecvError::Disconnected) if a stop was requested
     pub fn try_recv(&self) -> Result<P, TryRecvError> {
-        self.primary_rx.try_recv()
+        self.try_recv_with_info().map(|(msg, _)| msg)
     }
 
-    pub fn send(&self, m: P) -> Result<(), SendError<P>> {
-        self.primary_tx.send(m)
+    // Same as try_recv but also returns the info msg was sent with
+    pub fn try_recv_with_info(&self) -> Result<(P, MsgInfo), TryRecvError> {
+        if self.stop_received.take() {
+            return Err(TryRecvError::Disconnected);
+        }
+        self.send_expired_timers();
+        loop {
+            return match self.primary_rx.try_recv()? {
+                Envelope::Timer(id, ..) if !self.timer_received(id) => continue,
+                Envelope::Msg(msg, info) | Envelope::Timer(_, msg, info) => Ok((msg, info)),
+                Envelope::Stop => Err(TryRecvError::Disconnected),
+            };
+        }
     }
 
-    pub fn clone_sender(&self) -> Sender<P> {
-        self.primary_tx.clone()
+    // Make run return after the messages already sent are dispatched
+    pub fn request_stop(&self) {
+        // Can't fail as we own primary_rx
+        self.primary_tx.stop();
     }
 
-    pub fn defer_try_recv(&self) -> Result<P, TryRecvError> {
-        self.defer_rx[self.other_defer()].try_recv()
+    // Called by a process fn to have action run when the transition
+    // it returns is made. The action runs after the exit fns and
+    // before the enter fns, it's ignored if the fn returns no
+    // transition, it isn't the first transition or it's invalid.
+    pub fn transition_action(&self, action: TransitionActionFn<SM, P, S>) {
+        self.transition_action.set(Some(action));
     }
 
-    pub fn defer_send(&self, m: P) -> Result<(), SendError<P>> {
-        self.defer_tx[self.current_defer()].send(m)
+    // Called by a process fn to set the kind of the transition it
+    // returns, the default is TransitionKind::External. Like
+    // transition_action it's reset before each process fn and
+    // ignored if the fn returns no transition.
+    pub fn transition_kind(&self, kind: TransitionKind) {
+        self.transition_kind.set(kind);
     }
 
-    pub fn next_defer(&mut self) {
-        self.current_defer_idx = (self.current_defer_idx + 1) % self.defer_tx.len();
+    // Timer support
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
     }
 
-    pub fn current_defer(&self) -> usize {
-        self.current_defer_idx
+    // Send msg to ourselves after delay
+    pub fn send_after(&self, delay: Duration, msg: P) -> TimerId {
+        let deadline = self.clock.now() + delay;
+        self.timers.borrow_mut().add(deadline, None, None, msg)
     }
 
-    pub fn other_defer(&self) -> usize {
-        (self.current_defer_idx + 1) % self.defer_tx.len()
+    // Send a clone of msg to ourselves every period until cancelled,
+    // returns TimerError::ZeroPeriod if period is zero
+    pub fn send_every(&self, period: Duration, msg: P) -> Result<TimerId, TimerError>
+    where
+        P: Clone,
+    {
+        self.add_periodic(period, None, msg)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    // Same as send_after but the timer is cancelled when the state
+    // calling this from its enter or process fn is exited. Called
+    // from elsewhere the timer is owned by the current state.
+    pub fn state_send_after(&self, delay: Duration, msg: P) -> TimerId {
+        let deadline = self.clock.now() + delay;
+        self.timers
+            .borrow_mut()
+            .add(deadline, None, Some(self.idx_running()), msg)
+    }
 
-    // Test SM with one state with one field and no enter or exit
-    #[test]
-    #[no_coverage]
-    fn test_sm_1s_no_enter_no_exit() {
-        #[derive(Debug)]
+    // Same as send_every but the timer is owned by a state,
+    // see state_send_after
+    pub fn state_send_every(&self, period: Duration, msg: P) -> Result<TimerId, TimerError>
+    where
+        P: Clone,
+    {
+        self.add_periodic(period, Some(self.idx_running()), msg)
+    }
+
+    fn add_periodic(
+        &self,
+        period: Duration,
+        idx_owner: Option<usize>,
+        msg: P,
+    ) -> Result<TimerId, TimerError>
+    where
+        P: Clone,
+    {
+        if period.is_zero() {
+            return Err(TimerError::ZeroPeriod);
+        }
+        let deadline = self.clock.now() + period;
+
+        Ok(self
+            .timers
+            .borrow_mut()
+            .add(deadline, Some((period, P::clone)), idx_owner, msg))
+    }
+
+    // The state whose enter or process fn is running, or the current
+    // state outside of them. It owns the timers started and is the
+    // state deferring the messages deferred now.
+    fn idx_running(&self) -> usize {
+        self.idx_running_state
+            .get()
+            .unwrap_or(self.idx_current_state)
+    }
+
+    // Returns true if the timer was pending or its msg hasn't
+    // been received yet, the msg is then never received
+    pub fn cancel_timer(&self, id: TimerId) -> bool {
+        self.timers.borrow_mut().cancel(id)
+    }
+
+    pub fn next_timer_deadline(&self) -> Option<Instant> {
+        self.timers.borrow().next_deadline()
+    }
+
+    pub fn pending_timers(&self) -> usize {
+        self.timers.borrow().len()
+    }
+
+    fn send_expired_timers(&self) {
+        let expired = self.timers.borrow_mut().expired(self.clock.now());
+        for (id, msg) in expired {
+            // Can't fail as we own primary_rx
+            assert!(self.primary_tx.send_timer(id, msg), "SNH");
+        }
+    }
+
+    // Returns false if the msg of timer id was received after the
+    // timer was cancelled, for instance by exiting the state that
+    // owns it, the msg must then be dropped
+    fn timer_received(&self, id: TimerId) -> bool {
+        self.timers.borrow_mut().received(id)
+    }
+
+    pub fn send(&self, m: P) -> Result<(), SendError<P>> {
+        self.primary_tx.send(m)
+    }
+
+    pub fn clone_sender(&self) -> MsgSender<P> {
+        self.primary_tx.clone()
+    }
+
+    pub fn defer_try_recv(&self) -> Result<P, TryRecvError> {
+        match self.defer_pop() {
+            Some(deferred) => Ok(deferred.msg),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    fn defer_pop(&self) -> Option<Deferred<P>> {
+        self.deferred.borrow_mut()[self.other_defer()].pop_front()
+    }
+
+    // Defer m as a new message, it's stamped like a sent message.
+    // Returns Err with m if defer_capacity is reached and the
+    // policy is DeferOverflowPolicy::Reject
+    pub fn defer_send(&self, m: P) -> Result<(), SendError<P>> {
+        self.defer_with_info(m, None, self.idx_running())
+    }
+
+    // Defer m, a clone of the message being dispatched, keeping its
+    // info and replays so it's replayed in the order it was first
+    // accepted. Outside of a dispatch it's the same as defer_send.
+    pub fn defer_current(&self, m: P) -> Result<(), SendError<P>> {
+        self.defer_current_by(m, self.idx_running())
+    }
+
+    // Same as defer_current, idx is the state deferring m
+    fn defer_current_by(&self, m: P, idx: usize) -> Result<(), SendError<P>> {
+        let current = self
+            .current_msg_info
+            .get()
+            .map(|info| (info, self.replaying.get().unwrap_or(0)));
+        self.defer_with_info(m, current, idx)
+    }
+
+    // Defer m with its info and replays, None if it's a new
+    // message, idx is the state deferring it
+    fn defer_with_info(
+        &self,
+        m: P,
+        accepted: Option<(MsgInfo, usize)>,
+        idx: usize,
+    ) -> Result<(), SendError<P>> {
+        let mut deferred = self.deferred.borrow_mut();
+        let depth = deferred[0].len() + deferred[1].len();
+        if self
+            .defer_capacity
+            .is_some_and(|capacity| depth >= capacity)
+        {
+            self.defer_overflows.set(self.defer_overflows.get() + 1);
+            match self.defer_overflow_policy {
+                DeferOverflowPolicy::Reject => return Err(SendError(m)),
+                DeferOverflowPolicy::DropOldest => {
+                    // The queue being replayed has the oldest messages
+                    let other = self.other_defer();
+                    let oldest = deferred[other]
+                        .pop_front()
+                        .or_else(|| deferred[self.current_defer()].pop_front());
+                    match oldest {
+                        Some(oldest) => self.defer_drop(&oldest.msg),
+                        None => {
+                            self.defer_drop(&m);
+                            return Ok(());
+                        }
+                    }
+                }
+                DeferOverflowPolicy::DropNewest => {
+                    self.defer_drop(&m);
+                    return Ok(());
+                }
+                DeferOverflowPolicy::ErrorState(_) => {
+                    self.defer_overflowed.set(true);
+                    self.defer_drop(&m);
+                    return Ok(());
+                }
+            }
+        }
+        let (info, replays) = accepted.unwrap_or_else(|| (self.primary_tx.stamp(), 0));
+        self.notify(|observer| observer.on_defer(self.state_ref(idx), &m));
+        deferred[self.current_defer()].push_back(Deferred {
+            msg: m,
+            info,
+            replays,
+        });
+
+        Ok(())
+    }
+
+    // Count and report a message dropped by the overflow policy
+    fn defer_drop(&self, msg: &P) {
+        self.defer_dropped.set(self.defer_dropped.get() + 1);
+        self.notify(|observer| observer.on_defer_dropped(msg));
+    }
+
+    // The index and clone fn of the first active state, or state
+    // about to be entered, that defers msg, see StateInfo::defer
+    fn deferred_by(&self, msg: &P) -> Option<(usize, CloneFn<P>)> {
+        self.states
+            .iter()
+            .enumerate()
+            .filter(|(idx, state)| {
+                state.active || (self.current_state_changed && self.idxs_enter_fns.contains(idx))
+            })
+            .find(|(_, state)| state.defers.iter().any(|event| (event)(msg)))
+            .and_then(|(idx, state)| state.defer_clone.map(|clone| (idx, clone)))
+    }
+
+    // Defer the message being dispatched, keeping its info, if an
+    // active state defers it. Returns false if none does. A message
+    // rejected by DeferOverflowPolicy::Reject is dropped, it's never
+    // processed by a state that defers it.
+    fn defer_declared(&self, msg: &P) -> bool {
+        match self.deferred_by(msg) {
+            Some((idx, clone)) => {
+                if let Err(SendError(rejected)) = self.defer_current_by(clone(msg), idx) {
+                    self.defer_drop(&rejected);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns true if deferred has been replayed defer_max_replays
+    // times or is older than defer_max_age
+    fn is_expired(&self, deferred: &Deferred<P>) -> bool {
+        self.defer_max_replays
+            .is_some_and(|max_replays| deferred.replays >= max_replays)
+            || self.defer_max_age.is_some_and(|max_age| {
+                self.clock
+                    .now()
+                    .saturating_duration_since(deferred.info.enqueued_at)
+                    >= max_age
+            })
+    }
+
+    // Replace the transition of the process fns with one
+    // to the error state of DeferOverflowPolicy::ErrorState
+    fn defer_overflow_transition(&mut self) {
+        if let DeferOverflowPolicy::ErrorState(error_state) = &self.defer_overflow_policy {
+            self.idx_transition_dest = Some(error_state.idx());
+            self.idx_transition_src = self.idx_current_state;
+            self.transition_dest_action = None;
+            self.transition_dest_kind = TransitionKind::External;
+        }
+    }
+
+    // The number of deferred messages
+    pub fn defer_depth(&self) -> usize {
+        let deferred = self.deferred.borrow();
+        deferred[0].len() + deferred[1].len()
+    }
+
+    // The number of times defer_send was called with
+    // defer_capacity deferred messages, including those
+    // rejected with DeferOverflowPolicy::Reject
+    pub fn defer_overflows(&self) -> u64 {
+        self.defer_overflows.get()
+    }
+
+    // The number of messages dropped by the defer overflow policy
+    pub fn defer_dropped(&self) -> u64 {
+        self.defer_dropped.get()
+    }
+
+    // The number of deferred messages dropped because of
+    // defer_max_replays or defer_max_age
+    pub fn defer_expired(&self) -> u64 {
+        self.defer_expired
+    }
+
+    pub fn next_defer(&mut self) {
+        self.current_defer_idx = (self.current_defer_idx + 1) % 2;
+    }
+
+    pub fn current_defer(&self) -> usize {
+        self.current_defer_idx
+    }
+
+    pub fn other_defer(&self) -> usize {
+        (self.current_defer_idx + 1) % 2
+    }
+}
+
+// Stops the executor when dropped, see Executor::stop_on_drop
+pub struct StopOnDrop<'a, SM, P, S = Transition>
+where
+    SM: Debug,
+    P: Debug,
+    S: StateId,
+{
+    executor: &'a mut Executor<SM, P, S>,
+    msg: Option<P>,
+}
+
+impl<SM, P, S> Deref for StopOnDrop<'_, SM, P, S>
+where
+    SM: Debug,
+    P: Debug,
+    S: StateId,
+{
+    type Target = Executor<SM, P, S>;
+
+    fn deref(&self) -> &Self::Target {
+        self.executor
+    }
+}
+
+impl<SM, P, S> DerefMut for StopOnDrop<'_, SM, P, S>
+where
+    SM: Debug,
+    P: Debug,
+    S: StateId,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.executor
+    }
+}
+
+impl<SM, P, S> Drop for StopOnDrop<'_, SM, P, S>
+where
+    SM: Debug,
+    P: Debug,
+    S: StateId,
+{
+    fn drop(&mut self) {
+        if let Some(msg) = self.msg.take() {
+            self.executor.stop_with(&msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Test SM with one state with one field and no enter or exit
+    #[test]
+    #[no_coverage]
+    fn test_sm_1s_no_enter_no_exit() {
+        #[derive(Debug)]
         pub struct StateMachine {
             state: i32,
         }
@@ -555,13 +2737,13 @@ mod test {
         println!("{:?}", NoMessages);
         println!("{:?}", sme.get_sm());
 
-        sme.dispatcher(&NoMessages);
+        sme.dispatcher(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
         assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
         assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
         assert_eq!(sme.get_sm().borrow().state, 1);
 
-        sme.dispatcher(&NoMessages);
+        sme.dispatcher(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
         assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 2);
         assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
@@ -618,12 +2800,12 @@ mod test {
         println!("{:?}", NoMessages);
         println!("{:?}", sme.get_sm());
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_sm().borrow().state, 1);
         assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
         assert_eq!(sme.get_current_state_name(), "state1");
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_sm().borrow().state, 2);
         assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
         assert_eq!(sme.get_current_state_name(), "state1");
@@ -692,12 +2874,12 @@ mod test {
         println!("{:?}", NoMessages);
         println!("{:?}", sme.get_sm());
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_sm().borrow().state, 1);
         assert_eq!(sme.get_state_name(IDX_STATE2), "state2");
         assert_eq!(sme.get_current_state_name(), "state2");
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_sm().borrow().state, 0);
         assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
         assert_eq!(sme.get_current_state_name(), "state1");
@@ -753,7 +2935,7 @@ mod test {
         assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
 
         // This will panic because state1 returns an invalid transition
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
     }
 
     #[test]
@@ -833,7 +3015,7 @@ mod test {
                 let sm = RefCell::new(StateMachine);
                 let sme = Executor::new(sm, MAX_STATES)
                     .state(StateInfo::new("state1", Self::state1))
-                    .state(StateInfo::new("state1", Self::state2).parent_idx(IDX_STATE1))
+                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
                     .build(IDX_STATE2)
                     .expect("Unexpected error initializing");
 
@@ -873,7 +3055,7 @@ mod test {
 
         // This will panic because state2 returns an invalid transition
         // to state1 which isn't a leaf
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
     }
 
     #[test]
@@ -925,7 +3107,7 @@ mod test {
         assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
 
         // This will panic because state1 returns an invalid transition
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
     }
 
     // Test SM with one state with one field
@@ -960,7 +3142,7 @@ mod test {
             }
 
             #[no_coverage]
-            fn state1_enter(&mut self, _msg: &Messages) {
+            fn state1_enter(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
                 self.state = 100;
             }
 
@@ -986,13 +3168,13 @@ mod test {
         println!("{:?}", Messages::Add { val: -1 });
         println!("{:?}", sme.get_sm());
 
-        sme.dispatch(&Messages::Add { val: 2 });
+        sme.dispatch(&Messages::Add { val: 2 }).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 1);
         assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
         assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
         assert_eq!(sme.get_sm().borrow().state, 102);
 
-        sme.dispatch(&Messages::Sub { val: 1 });
+        sme.dispatch(&Messages::Sub { val: 1 }).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 1);
         assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 2);
         assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
@@ -1063,7 +3245,7 @@ mod test {
         println!("{:?}", Message::Add { val: -2 });
         println!("{:?}", sme.get_sm());
 
-        sme.dispatch(&Message::Add { val: 2 });
+        sme.dispatch(&Message::Add { val: 2 }).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
         assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
         assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
@@ -1072,7 +3254,7 @@ mod test {
         assert_eq!(sme.get_state_exit_cnt(IDX_STATE2), 0);
         assert_eq!(sme.get_sm().borrow().state, 2);
 
-        sme.dispatch(&Message::Add { val: -1 });
+        sme.dispatch(&Message::Add { val: -1 }).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
         assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
         assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
@@ -1145,7 +3327,7 @@ mod test {
         println!("{:?}", Message::Add { val: -1 });
         println!("{:?}", sme.get_sm());
 
-        sme.dispatch(&Message::Add { val: 2 });
+        sme.dispatch(&Message::Add { val: 2 }).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_PARENT), 0);
         assert_eq!(sme.get_state_process_cnt(IDX_PARENT), 1);
         assert_eq!(sme.get_state_exit_cnt(IDX_PARENT), 0);
@@ -1154,7 +3336,7 @@ mod test {
         assert_eq!(sme.get_state_exit_cnt(IDX_CHILD), 0);
         assert_eq!(sme.get_sm().borrow().state, 2);
 
-        sme.dispatch(&Message::Sub { val: 1 });
+        sme.dispatch(&Message::Sub { val: 1 }).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_PARENT), 0);
         assert_eq!(sme.get_state_process_cnt(IDX_PARENT), 2);
         assert_eq!(sme.get_state_exit_cnt(IDX_PARENT), 0);
@@ -1212,7 +3394,7 @@ mod test {
                 sme
             }
 
-            fn base_enter(&mut self, _msg: &NoMessages) {}
+            fn base_enter(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             // This state has idx 0
             #[no_coverage]
@@ -1221,7 +3403,7 @@ mod test {
             }
 
             #[no_coverage]
-            fn initial_enter(&mut self, _msg: &NoMessages) {}
+            fn initial_enter(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             // This state has idx 0
             #[no_coverage]
@@ -1234,10 +3416,10 @@ mod test {
             }
 
             #[no_coverage]
-            fn initial_exit(&mut self, _msg: &NoMessages) {}
+            fn initial_exit(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             #[no_coverage]
-            fn other_enter(&mut self, _msg: &NoMessages) {}
+            fn other_enter(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             // This state has idx 0
             #[no_coverage]
@@ -1246,7 +3428,7 @@ mod test {
             }
 
             #[no_coverage]
-            fn other_exit(&mut self, _msg: &NoMessages) {}
+            fn other_exit(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
         }
 
         // For code coverage
@@ -1266,7 +3448,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
         assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
@@ -1277,7 +3459,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
         assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
@@ -1288,7 +3470,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
         assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
@@ -1299,7 +3481,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
         assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
@@ -1310,7 +3492,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 2);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 2);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
         assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
@@ -1381,7 +3563,7 @@ mod test {
             }
 
             #[no_coverage]
-            fn initial_base_enter(&mut self, _msg: &NoMessages) {}
+            fn initial_base_enter(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             // This state has hdl 0
             #[no_coverage]
@@ -1394,10 +3576,10 @@ mod test {
             }
 
             #[no_coverage]
-            fn initial_base_exit(&mut self, _msg: &NoMessages) {}
+            fn initial_base_exit(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             #[no_coverage]
-            fn initial_enter(&mut self, _msg: &NoMessages) {}
+            fn initial_enter(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             // This state has hdl 0
             #[no_coverage]
@@ -1410,10 +3592,10 @@ mod test {
             }
 
             #[no_coverage]
-            fn initial_exit(&mut self, _msg: &NoMessages) {}
+            fn initial_exit(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             #[no_coverage]
-            fn other_base_enter(&mut self, _msg: &NoMessages) {}
+            fn other_base_enter(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             // This state has hdl 0
             #[no_coverage]
@@ -1426,10 +3608,10 @@ mod test {
             }
 
             #[no_coverage]
-            fn other_base_exit(&mut self, _msg: &NoMessages) {}
+            fn other_base_exit(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             #[no_coverage]
-            fn other_enter(&mut self, _msg: &NoMessages) {}
+            fn other_enter(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
 
             // This state has hdl 0
             #[no_coverage]
@@ -1438,7 +3620,7 @@ mod test {
             }
 
             #[no_coverage]
-            fn other_exit(&mut self, _msg: &NoMessages) {}
+            fn other_exit(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {}
         }
 
         // For code coverage
@@ -1461,7 +3643,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 1);
         assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 1);
@@ -1475,7 +3657,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 1);
         assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 1);
@@ -1489,7 +3671,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 2);
         assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 2);
@@ -1503,7 +3685,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 2);
         assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 2);
@@ -1517,7 +3699,7 @@ mod test {
         assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 2);
         assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 2);
 
-        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages).unwrap();
         assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 3);
         assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
         assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 3);
@@ -1562,7 +3744,12 @@ mod test {
                     .build(IDX_STATE1)
                 {
                     Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::Cycle {
+                            states: vec!["state1".to_owned()]
+                        }
+                    ),
                 }
             }
 
@@ -1615,7 +3802,12 @@ mod test {
                     .build(IDX_STATE1)
                 {
                     Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::Cycle {
+                            states: vec!["state1".to_owned()]
+                        }
+                    ),
                 }
             }
 
@@ -1678,7 +3870,12 @@ mod test {
                     .build(IDX_STATE1)
                 {
                     Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::Cycle {
+                            states: vec!["state1".to_owned(), "state2".to_owned()]
+                        }
+                    ),
                 }
             }
 
@@ -1741,7 +3938,12 @@ mod test {
                     .build(IDX_STATE1)
                 {
                     Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::Cycle {
+                            states: vec!["state1".to_owned(), "state2".to_owned()]
+                        }
+                    ),
                 }
             }
 
@@ -1825,7 +4027,16 @@ mod test {
                     .build(IDX_STATE1)
                 {
                     Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::Cycle {
+                            states: vec![
+                                "state1".to_owned(),
+                                "state2".to_owned(),
+                                "state3".to_owned()
+                            ]
+                        }
+                    ),
                 }
             }
 
@@ -1881,4 +4092,2572 @@ mod test {
 
         StateMachine::new();
     }
+
+    #[test]
+    #[no_coverage]
+    fn test_typed_state_ids() {
+        // StateMachine simply transitions back and forth
+        // between initial and other using typed state ids.
+        //
+        //                base
+        //        --------^  ^-------
+        //       /                   \
+        //      /                     \
+        //    other   <======>   initial
+
+        #[derive(Debug)]
+        struct StateMachine;
+
+        // Create a Protocol with no messages
+        #[derive(Debug)]
+        struct NoMessages;
+
+        state_ids! {
+            enum States {
+                Base,
+                Initial,
+                Other,
+            }
+        }
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages, States> {
+                let sm = RefCell::new(StateMachine);
+                Executor::new(sm, States::COUNT)
+                    .state_at(States::Base, StateInfo::new("base", Self::base))
+                    .state_at(
+                        States::Initial,
+                        StateInfo::new("initial", Self::initial).parent_idx(States::Base),
+                    )
+                    .state_at(
+                        States::Other,
+                        StateInfo::new("other", Self::other).parent_idx(States::Base),
+                    )
+                    .build(States::Initial)
+                    .expect("Unexpected error initializing")
+            }
+
+            #[no_coverage]
+            fn base(
+                &mut self,
+                _e: &Executor<Self, NoMessages, States>,
+                _msg: &NoMessages,
+            ) -> StateResult<States> {
+                (Handled::Yes, None)
+            }
+
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                _e: &Executor<Self, NoMessages, States>,
+                _msg: &NoMessages,
+            ) -> StateResult<States> {
+                (Handled::Yes, Some(States::Other))
+            }
+
+            #[no_coverage]
+            fn other(
+                &mut self,
+                _e: &Executor<Self, NoMessages, States>,
+                _msg: &NoMessages,
+            ) -> StateResult<States> {
+                (Handled::Yes, Some(States::Initial))
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(States::COUNT, 3);
+        assert_eq!(sme.get_state_name(States::Base), "base");
+        assert_eq!(sme.get_current_state_name(), "initial");
+
+        sme.dispatch(&NoMessages).unwrap();
+        assert_eq!(sme.get_current_state_name(), "other");
+        assert_eq!(sme.get_state_process_cnt(States::Initial), 1);
+        assert_eq!(sme.get_state_process_cnt(States::Other), 0);
+
+        sme.dispatch(&NoMessages).unwrap();
+        assert_eq!(sme.get_current_state_name(), "initial");
+        assert_eq!(sme.get_state_process_cnt(States::Initial), 1);
+        assert_eq!(sme.get_state_process_cnt(States::Other), 1);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_typed_state_ids_out_of_order() {
+        // The states are added in a different order than declared
+        // in States, they're placed at their ids so the transitions
+        // still target the right states. A duplicate id or a missing
+        // state fails to build.
+
+        #[derive(Debug)]
+        struct StateMachine;
+
+        // Create a Protocol with no messages
+        #[derive(Debug)]
+        struct NoMessages;
+
+        state_ids! {
+            enum States {
+                State1,
+                State2,
+            }
+        }
+
+        impl StateMachine {
+            #[no_coverage]
+            fn build_out_of_order() {
+                let sm = RefCell::new(StateMachine);
+                let mut sme = Executor::new(sm, States::COUNT)
+                    .state_at(States::State2, StateInfo::new("state2", Self::state2))
+                    .state_at(States::State1, StateInfo::new("state1", Self::state1))
+                    .build(States::State1)
+                    .expect("Unexpected error initializing");
+                assert_eq!(sme.get_state_name(States::State1), "state1");
+                assert_eq!(sme.get_state_name(States::State2), "state2");
+
+                sme.dispatch(&NoMessages).unwrap();
+                assert_eq!(sme.get_current_state_name(), "state2");
+                sme.dispatch(&NoMessages).unwrap();
+                assert_eq!(sme.get_current_state_name(), "state1");
+            }
+
+            #[no_coverage]
+            fn build_duplicate_id() {
+                let sm = RefCell::new(StateMachine);
+                match Executor::new(sm, States::COUNT)
+                    .state_at(States::State1, StateInfo::new("state1", Self::state1))
+                    .state_at(States::State1, StateInfo::new("state2", Self::state2))
+                    .build(States::State1)
+                {
+                    Ok(_) => panic!("Expected the duplicate id to be detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::DuplicateId {
+                            state: "state2".to_owned(),
+                            id: 0,
+                        }
+                    ),
+                }
+            }
+
+            #[no_coverage]
+            fn build_missing_state() {
+                let sm = RefCell::new(StateMachine);
+                match Executor::new(sm, States::COUNT)
+                    .state_at(States::State1, StateInfo::new("state1", Self::state1))
+                    .build(States::State1)
+                {
+                    Ok(_) => panic!("Expected the missing State2 to be detected"),
+                    Err(e) => assert_eq!(e, BuildError::MissingStates { count: 2, len: 1 }),
+                }
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages, States>,
+                _msg: &NoMessages,
+            ) -> StateResult<States> {
+                (Handled::Yes, Some(States::State2))
+            }
+
+            #[no_coverage]
+            fn state2(
+                &mut self,
+                _e: &Executor<Self, NoMessages, States>,
+                _msg: &NoMessages,
+            ) -> StateResult<States> {
+                (Handled::Yes, Some(States::State1))
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        StateMachine::build_out_of_order();
+        StateMachine::build_duplicate_id();
+        StateMachine::build_missing_state();
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_build_no_states() {
+        #[derive(Debug)]
+        struct StateMachine;
+
+        #[derive(Debug)]
+        struct NoMessages;
+
+        let sm = RefCell::new(StateMachine);
+        match Executor::<StateMachine, NoMessages>::new(sm, 0).build(0) {
+            Ok(_) => panic!("Expected no states to be detected"),
+            Err(e) => {
+                assert_eq!(e, BuildError::NoStates);
+                assert_eq!(e.to_string(), "No states");
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_build_invalid_states() {
+        #[derive(Debug)]
+        struct StateMachine;
+
+        #[derive(Debug)]
+        struct NoMessages;
+
+        const MAX_STATES: usize = 4;
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+        const IDX_STATE3: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                (Handled::Yes, None)
+            }
+
+            #[no_coverage]
+            fn state2(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                (Handled::Yes, None)
+            }
+        }
+
+        // Parent isn't a state
+        let sm = RefCell::new(StateMachine);
+        match Executor::new(sm, MAX_STATES)
+            .state(StateInfo::new("state1", StateMachine::state1).parent_idx(5))
+            .build(IDX_STATE1)
+        {
+            Ok(_) => panic!("Expected the invalid parent to be detected"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::ParentOutOfRange {
+                    state: "state1".to_owned(),
+                    idx_parent: 5
+                }
+            ),
+        }
+
+        // Duplicate names
+        let sm = RefCell::new(StateMachine);
+        match Executor::new(sm, MAX_STATES)
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .state(StateInfo::new("state1", StateMachine::state2))
+            .build(IDX_STATE1)
+        {
+            Ok(_) => panic!("Expected the duplicate name to be detected"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::DuplicateName {
+                    name: "state1".to_owned(),
+                    idx_first: 0,
+                    idx_second: 1
+                }
+            ),
+        }
+
+        // Initial state isn't a state
+        let sm = RefCell::new(StateMachine);
+        match Executor::new(sm, MAX_STATES)
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .build(IDX_STATE2)
+        {
+            Ok(_) => panic!("Expected the invalid initial state to be detected"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::InitialStateOutOfRange {
+                    idx_initial: IDX_STATE2,
+                    len: 1
+                }
+            ),
+        }
+
+        // Initial state has a child
+        let sm = RefCell::new(StateMachine);
+        match Executor::new(sm, MAX_STATES)
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .state(StateInfo::new("state2", StateMachine::state2).parent_idx(IDX_STATE1))
+            .build(IDX_STATE1)
+        {
+            Ok(_) => panic!("Expected the composite initial state to be detected"),
+            Err(e) => {
+                assert_eq!(
+                    e,
+                    BuildError::InitialStateNotTarget {
+                        state: "state1".to_owned(),
+                        targets: vec!["state2".to_owned()]
+                    }
+                );
+                assert_eq!(
+                    e.to_string(),
+                    "'state1' is not a valid initial state, only [\"state2\"] are allowed"
+                );
+            }
+        }
+
+        // The targets include composites with an initial child
+        // but not history states
+        let sm = RefCell::new(StateMachine);
+        match Executor::new(sm, MAX_STATES)
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .state(
+                StateInfo::new("state2", StateMachine::state2)
+                    .parent_idx(IDX_STATE1)
+                    .initial_child(IDX_STATE3),
+            )
+            .state(StateInfo::new("state3", StateMachine::state2).parent_idx(IDX_STATE2))
+            .state(StateInfo::new_history("history", History::Shallow).parent_idx(IDX_STATE2))
+            .build(IDX_STATE1)
+        {
+            Ok(_) => panic!("Expected the composite initial state to be detected"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::InitialStateNotTarget {
+                    state: "state1".to_owned(),
+                    targets: vec!["state2".to_owned(), "state3".to_owned()]
+                }
+            ),
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_invalid_transition_policy() {
+        // state1 returns a transition to base which isn't a leaf
+        //
+        //        base
+        //       /    \
+        //  state1    error
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            hook_cnt: usize,
+            invalid: Option<InvalidTransition>,
+        }
+
+        #[derive(Debug)]
+        struct NoMessages;
+
+        const MAX_STATES: usize = 3;
+        const IDX_BASE: usize = 0;
+        const IDX_STATE1: usize = 1;
+        const IDX_ERROR: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn build_with(
+                policy: InvalidTransitionPolicy<Self, NoMessages>,
+            ) -> Result<Executor<Self, NoMessages>, BuildError> {
+                let sm = RefCell::new(StateMachine::default());
+                Executor::new(sm, MAX_STATES)
+                    .state(StateInfo::new("base", Self::base))
+                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_BASE))
+                    .state(StateInfo::new("error", Self::error).parent_idx(IDX_BASE))
+                    .invalid_transition_policy(policy)
+                    .build(IDX_STATE1)
+            }
+
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                (Handled::Yes, None)
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                (Handled::Yes, Some(IDX_BASE))
+            }
+
+            #[no_coverage]
+            fn error(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                (Handled::Yes, None)
+            }
+
+            #[no_coverage]
+            fn hook(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                invalid: &InvalidTransition,
+                _msg: &NoMessages,
+            ) {
+                assert_eq!(e.get_current_state_name(), "state1");
+                self.hook_cnt += 1;
+                self.invalid = Some(invalid.clone());
+            }
+        }
+
+        let expected = InvalidTransition {
+            idx_src: IDX_STATE1,
+            src: "state1".to_owned(),
+            idx_dest: IDX_BASE,
+            msg: "NoMessages".to_owned(),
+        };
+
+        // ReturnError, the transition is ignored
+        let mut sme = StateMachine::build_with(InvalidTransitionPolicy::ReturnError).unwrap();
+        assert_eq!(
+            sme.dispatch(&NoMessages),
+            Err(DispatchError::InvalidTransition(expected.clone()))
+        );
+        assert_eq!(sme.get_current_state_name(), "state1");
+        assert_eq!(
+            sme.dispatcher(&NoMessages).unwrap_err().to_string(),
+            "State 'state1' returned 0 which is not a valid transition target, msg=NoMessages"
+        );
+
+        // ErrorState, transition to error instead
+        let mut sme =
+            StateMachine::build_with(InvalidTransitionPolicy::ErrorState(IDX_ERROR)).unwrap();
+        assert!(sme.dispatch(&NoMessages).unwrap());
+        assert_eq!(sme.get_current_state_name(), "error");
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
+
+        // Hook, the transition is ignored and the hook is invoked
+        let mut sme =
+            StateMachine::build_with(InvalidTransitionPolicy::Hook(StateMachine::hook)).unwrap();
+        sme.dispatcher(&NoMessages).unwrap();
+        assert_eq!(sme.get_current_state_name(), "state1");
+        assert_eq!(sme.get_sm().borrow().hook_cnt, 1);
+        assert_eq!(sme.get_sm().borrow().invalid, Some(expected));
+
+        // The error state must be a leaf
+        match StateMachine::build_with(InvalidTransitionPolicy::ErrorState(IDX_BASE)) {
+            Ok(_) => panic!("Expected the invalid error state to be detected"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::InvalidErrorState {
+                    idx_error_state: IDX_BASE,
+                    targets: vec!["state1".to_owned(), "error".to_owned()]
+                }
+            ),
+        }
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_enter_exit_fns_use_executor() {
+        // The enter fn of state1 sends a message to itself and
+        // the exit fn records the name of the next state.
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            exit_next_state: String,
+        }
+
+        #[derive(Debug, PartialEq)]
+        enum Messages {
+            Entered { name: String },
+            Next,
+        }
+
+        const MAX_STATES: usize = 2;
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, Messages> {
+                let sm = RefCell::new(StateMachine::default());
+                Executor::new(sm, MAX_STATES)
+                    .state(
+                        StateInfo::new("state1", Self::state1)
+                            .enter_fn(Self::state1_enter)
+                            .exit_fn(Self::state1_exit),
+                    )
+                    .state(StateInfo::new("state2", Self::state2))
+                    .build(IDX_STATE1)
+                    .expect("Unexpected error initializing")
+            }
+
+            #[no_coverage]
+            fn state1_enter(&mut self, e: &Executor<Self, Messages>, _msg: &Messages) {
+                e.send(Messages::Entered {
+                    name: e.get_current_state_name().to_owned(),
+                })
+                .unwrap();
+            }
+
+            #[no_coverage]
+            fn state1_exit(&mut self, e: &Executor<Self, Messages>, _msg: &Messages) {
+                self.exit_next_state = e.get_current_state_name().to_owned();
+            }
+
+            #[no_coverage]
+            fn state1(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Next => (Handled::Yes, Some(IDX_STATE2)),
+                    _ => (Handled::Yes, None),
+                }
+            }
+
+            #[no_coverage]
+            fn state2(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::Yes, None)
+            }
+        }
+
+        let mut sme = StateMachine::new();
+        sme.dispatch(&Messages::Next).unwrap();
+        assert_eq!(
+            sme.try_recv(),
+            Ok(Messages::Entered {
+                name: "state1".to_owned()
+            })
+        );
+        assert_eq!(sme.get_sm().borrow().exit_next_state, "state2");
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 1);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_start_stop() {
+        //        base
+        //       /    \
+        //  initial    other
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            log: Vec<String>,
+        }
+
+        #[derive(Debug, Default)]
+        struct NoMessages;
+
+        const MAX_STATES: usize = 3;
+        const IDX_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine::default());
+                Executor::new(sm, MAX_STATES)
+                    .state(
+                        StateInfo::new("base", Self::base)
+                            .enter_fn(Self::enter)
+                            .exit_fn(Self::base_exit),
+                    )
+                    .state(
+                        StateInfo::new("initial", Self::initial)
+                            .parent_idx(IDX_BASE)
+                            .enter_fn(Self::enter)
+                            .exit_fn(Self::exit),
+                    )
+                    .state(
+                        StateInfo::new("other", Self::other)
+                            .parent_idx(IDX_BASE)
+                            .exit_fn(Self::other_exit),
+                    )
+                    .build(IDX_INITIAL)
+                    .expect("Unexpected error initializing")
+            }
+
+            #[no_coverage]
+            fn enter(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {
+                self.log.push("enter".to_owned());
+            }
+
+            #[no_coverage]
+            fn exit(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {
+                self.log.push("exit".to_owned());
+            }
+
+            #[no_coverage]
+            fn base_exit(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {
+                self.log.push("base_exit".to_owned());
+            }
+
+            #[no_coverage]
+            fn other_exit(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) {
+                self.log.push("other_exit".to_owned());
+            }
+
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                (Handled::Yes, None)
+            }
+
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                (Handled::Yes, Some(IDX_OTHER))
+            }
+
+            #[no_coverage]
+            fn other(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                (Handled::Yes, None)
+            }
+        }
+
+        let mut sme = StateMachine::new();
+
+        // start enters base and initial immediately
+        sme.start();
+        assert_eq!(sme.get_sm().borrow().log, vec!["enter", "enter"]);
+        assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
+        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 1);
+        assert!(sme.states[IDX_BASE].active);
+        assert!(sme.states[IDX_INITIAL].active);
+
+        // A second start does nothing and dispatch doesn't enter again
+        sme.start();
+        sme.dispatch(&NoMessages).unwrap();
+        assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
+        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 1);
+        assert_eq!(sme.get_current_state_name(), "other");
+
+        // other has no enter fn but is still active once entered
+        sme.dispatch(&NoMessages).unwrap();
+        assert!(sme.states[IDX_OTHER].active);
+
+        // stop exits other then base and returns to initial
+        sme.get_sm().borrow_mut().log.clear();
+        sme.stop();
+        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 1);
+        assert_eq!(sme.get_sm().borrow().log, vec!["other_exit", "base_exit"]);
+        assert!(sme.states.iter().all(|state| !state.active));
+        assert_eq!(sme.get_current_state_name(), "initial");
+
+        // Stopping a stopped executor does nothing
+        sme.stop();
+        assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 1);
+
+        // The guard stops the executor when dropped
+        {
+            let mut sme = sme.stop_on_drop(NoMessages);
+            sme.start();
+            assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 2);
+            assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 2);
+        }
+        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 2);
+        assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 2);
+        assert_eq!(sme.get_current_state_name(), "initial");
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_dispatch_mut() {
+        // child takes the payload and returns Handled::No so
+        // base sees the message without the payload.
+        //
+        //   base
+        //    |
+        //  child
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            taken: Vec<u8>,
+            base_buf_len: Option<usize>,
+        }
+
+        #[derive(Debug)]
+        enum Messages {
+            Data { buf: Vec<u8> },
+        }
+
+        const MAX_STATES: usize = 2;
+        const IDX_BASE: usize = 0;
+        const IDX_CHILD: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, Messages> {
+                let sm = RefCell::new(StateMachine::default());
+                Executor::new(sm, MAX_STATES)
+                    .state(StateInfo::new_mut("base", Self::base))
+                    .state(StateInfo::new_mut("child", Self::child).parent_idx(IDX_BASE))
+                    .build(IDX_CHILD)
+                    .expect("Unexpected error initializing")
+            }
+
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, msg: &mut Messages) -> StateResult {
+                match msg {
+                    Messages::Data { buf } => self.base_buf_len = Some(buf.len()),
+                }
+                (Handled::Yes, None)
+            }
+
+            #[no_coverage]
+            fn child(&mut self, _e: &Executor<Self, Messages>, msg: &mut Messages) -> StateResult {
+                match msg {
+                    Messages::Data { buf } => self.taken = std::mem::take(buf),
+                }
+                (Handled::No, None)
+            }
+        }
+
+        let mut sme = StateMachine::new();
+
+        // The buffer is moved to the sm and base sees it empty
+        let buf = vec![1, 2, 3];
+        let buf_ptr = buf.as_ptr();
+        let mut msg = Messages::Data { buf };
+        sme.dispatch_mut(&mut msg).unwrap();
+        assert_eq!(sme.get_sm().borrow().taken, vec![1, 2, 3]);
+        assert_eq!(sme.get_sm().borrow().taken.as_ptr(), buf_ptr);
+        assert_eq!(sme.get_sm().borrow().base_buf_len, Some(0));
+        match msg {
+            Messages::Data { ref buf } => assert!(buf.is_empty()),
+        }
+
+        // A &msg can't be dispatched to a state needing a &mut msg
+        assert_eq!(
+            sme.dispatch(&msg),
+            Err(DispatchError::MutProcessWithRef {
+                idx: IDX_CHILD,
+                state: "child".to_owned()
+            })
+        );
+        assert_eq!(sme.get_state_process_cnt(IDX_CHILD), 1);
+
+        sme.dispatcher_owned(Messages::Data { buf: vec![4] })
+            .unwrap();
+        assert_eq!(sme.get_sm().borrow().taken, vec![4]);
+        assert_eq!(sme.get_state_process_cnt(IDX_CHILD), 2);
+        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 2);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_send_every_zero_period() {
+        // A zero period would make recv loop forever so it's rejected
+
+        #[derive(Debug)]
+        struct StateMachine;
+
+        #[derive(Debug, Clone)]
+        struct NoMessages;
+
+        const MAX_STATES: usize = 1;
+        const IDX_STATE1: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                (Handled::Yes, None)
+            }
+        }
+
+        let sme = Executor::new(RefCell::new(StateMachine), MAX_STATES)
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .build(IDX_STATE1)
+            .unwrap();
+        assert_eq!(
+            sme.send_every(Duration::ZERO, NoMessages),
+            Err(TimerError::ZeroPeriod)
+        );
+        assert_eq!(
+            sme.state_send_every(Duration::ZERO, NoMessages),
+            Err(TimerError::ZeroPeriod)
+        );
+        assert_eq!(sme.pending_timers(), 0);
+        assert!(sme.try_recv().is_err());
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_timers() {
+        // wait starts a 2s state timer in its enter fn, it's
+        // cancelled if wait is exited before it expires.
+
+        #[derive(Debug, Default)]
+        struct StateMachine;
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Messages {
+            Go,
+            Back,
+            Timeout,
+            Tick,
+        }
+
+        const MAX_STATES: usize = 3;
+        const IDX_WAIT: usize = 0;
+        const IDX_OTHER: usize = 1;
+        const IDX_TIMED_OUT: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new(clock: &ManualClock) -> Executor<Self, Messages> {
+                let sm = RefCell::new(StateMachine);
+                Executor::new(sm, MAX_STATES)
+                    .state(StateInfo::new("wait", Self::wait).enter_fn(Self::wait_enter))
+                    .state(StateInfo::new("other", Self::other))
+                    .state(StateInfo::new("timed_out", Self::timed_out))
+                    .clock(Arc::new(clock.clone()))
+                    .build(IDX_WAIT)
+                    .expect("Unexpected error initializing")
+            }
+
+            #[no_coverage]
+            fn wait_enter(&mut self, e: &Executor<Self, Messages>, _msg: &Messages) {
+                e.state_send_after(Duration::from_secs(2), Messages::Timeout);
+            }
+
+            #[no_coverage]
+            fn wait(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Go => (Handled::Yes, Some(IDX_OTHER)),
+                    Messages::Timeout => (Handled::Yes, Some(IDX_TIMED_OUT)),
+                    _ => (Handled::Yes, None),
+                }
+            }
+
+            #[no_coverage]
+            fn other(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Back => (Handled::Yes, Some(IDX_WAIT)),
+                    _ => (Handled::Yes, None),
+                }
+            }
+
+            #[no_coverage]
+            fn timed_out(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::Yes, None)
+            }
+        }
+
+        let clock = ManualClock::new();
+        let second = Duration::from_secs(1);
+        let mut sme = StateMachine::new(&clock);
+        sme.start_with(&Messages::Go);
+        assert_eq!(sme.pending_timers(), 1);
+        assert_eq!(sme.next_timer_deadline(), Some(clock.now() + 2 * second));
+
+        // Leaving wait before the timer expires cancels it
+        clock.advance(second);
+        assert_eq!(sme.try_recv(), Err(TryRecvError::Empty));
+        sme.dispatch(&Messages::Go).unwrap();
+        assert_eq!(sme.get_current_state_name(), "other");
+        assert_eq!(sme.pending_timers(), 0);
+        clock.advance(2 * second);
+        assert_eq!(sme.try_recv(), Err(TryRecvError::Empty));
+
+        // Back to wait, which starts a new timer when entered
+        sme.dispatch(&Messages::Back).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(sme.pending_timers(), 1);
+        clock.advance(2 * second);
+        let msg = sme.try_recv().unwrap();
+        assert_eq!(msg, Messages::Timeout);
+        sme.dispatch(&msg).unwrap();
+        assert_eq!(sme.get_current_state_name(), "timed_out");
+
+        // Periodic and one shot timers aren't owned by a state
+        let id = sme.send_every(second, Messages::Tick).unwrap();
+        sme.send_after(second, Messages::Go);
+        clock.advance(second);
+        assert_eq!(sme.try_recv(), Ok(Messages::Tick));
+        assert_eq!(sme.try_recv(), Ok(Messages::Go));
+        clock.advance(second);
+        assert_eq!(sme.try_recv(), Ok(Messages::Tick));
+        assert!(sme.cancel_timer(id));
+        assert!(!sme.cancel_timer(id));
+        clock.advance(second);
+        assert_eq!(sme.try_recv(), Err(TryRecvError::Empty));
+
+        // A periodic state timer is cancelled when its state is exited
+        let mut sme = StateMachine::new(&clock);
+        sme.start_with(&Messages::Go);
+        sme.state_send_every(second, Messages::Tick).unwrap();
+        assert_eq!(sme.pending_timers(), 2);
+        clock.advance(second);
+        assert_eq!(sme.try_recv(), Ok(Messages::Tick));
+        sme.dispatch(&Messages::Go).unwrap();
+        assert_eq!(sme.pending_timers(), 0);
+        clock.advance(second);
+        assert_eq!(sme.try_recv(), Err(TryRecvError::Empty));
+
+        // Two state timers expire together, the first transitions out
+        // of wait so the msg of the second, already expired, is dropped
+        let mut sme = StateMachine::new(&clock);
+        sme.start_with(&Messages::Go);
+        let id = sme.state_send_after(2 * second, Messages::Tick);
+        clock.advance(2 * second);
+        let msg = sme.try_recv().unwrap();
+        assert_eq!(msg, Messages::Timeout);
+        sme.dispatch(&msg).unwrap();
+        assert_eq!(sme.get_current_state_name(), "timed_out");
+        assert!(!sme.cancel_timer(id));
+        assert_eq!(sme.try_recv(), Err(TryRecvError::Empty));
+
+        // recv waits for the timer with the SystemClock
+        let sme = StateMachine::new(&clock).clock(Arc::new(SystemClock));
+        sme.send_after(Duration::from_millis(10), Messages::Tick);
+        assert_eq!(sme.recv(), Ok(Messages::Tick));
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_parallel() {
+        //              base
+        //            /      \
+        //     device(||)     failed
+        //      /       \
+        // connection    power
+        //   /    \      /   \
+        // disc  conn  off    on
+
+        #[derive(Debug, Default)]
+        struct StateMachine;
+
+        #[derive(Debug)]
+        enum Messages {
+            Tick,
+            Connect,
+            PowerOn,
+            Fail,
+            Reset,
+        }
+
+        // Records the enter and exit events
+        struct Events(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl Observer<Messages> for Events {
+            fn on_enter(&mut self, state: StateRef, _msg: &Messages) {
+                self.0.lock().unwrap().push(format!("+{}", state.name));
+            }
+
+            fn on_exit(&mut self, state: StateRef, _msg: &Messages) {
+                self.0.lock().unwrap().push(format!("-{}", state.name));
+            }
+        }
+
+        const MAX_STATES: usize = 9;
+        const IDX_BASE: usize = 0;
+        const IDX_DEVICE: usize = 1;
+        const IDX_CONNECTION: usize = 2;
+        const IDX_DISCONNECTED: usize = 3;
+        const IDX_CONNECTED: usize = 4;
+        const IDX_POWER: usize = 5;
+        const IDX_OFF: usize = 6;
+        const IDX_ON: usize = 7;
+        const IDX_FAILED: usize = 8;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn not_handled(
+                &mut self,
+                _e: &Executor<Self, Messages>,
+                _msg: &Messages,
+            ) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn device(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Fail => (Handled::Yes, Some(IDX_FAILED)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn disconnected(
+                &mut self,
+                _e: &Executor<Self, Messages>,
+                msg: &Messages,
+            ) -> StateResult {
+                match msg {
+                    Messages::Tick => (Handled::Yes, None),
+                    Messages::Connect => (Handled::Yes, Some(IDX_CONNECTED)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn off(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Tick => (Handled::Yes, None),
+                    Messages::PowerOn => (Handled::Yes, Some(IDX_ON)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn failed(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Reset => (Handled::Yes, Some(IDX_ON)),
+                    _ => (Handled::No, None),
+                }
+            }
+        }
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sme = Executor::new(RefCell::new(StateMachine), MAX_STATES)
+            .state(StateInfo::new("base", StateMachine::not_handled))
+            .state(
+                StateInfo::new("device", StateMachine::device)
+                    .parent_idx(IDX_BASE)
+                    .parallel(),
+            )
+            .state(StateInfo::new("connection", StateMachine::not_handled).parent_idx(IDX_DEVICE))
+            .state(
+                StateInfo::new("disconnected", StateMachine::disconnected)
+                    .parent_idx(IDX_CONNECTION),
+            )
+            .state(
+                StateInfo::new("connected", StateMachine::not_handled).parent_idx(IDX_CONNECTION),
+            )
+            .state(StateInfo::new("power", StateMachine::not_handled).parent_idx(IDX_DEVICE))
+            .state(StateInfo::new("off", StateMachine::off).parent_idx(IDX_POWER))
+            .state(StateInfo::new("on", StateMachine::not_handled).parent_idx(IDX_POWER))
+            .state(StateInfo::new("failed", StateMachine::failed).parent_idx(IDX_BASE))
+            .observer(Events(events.clone()))
+            .build(IDX_DISCONNECTED)
+            .unwrap();
+        let take_events = || events.lock().unwrap().drain(..).collect::<Vec<String>>();
+
+        // The power region is entered at its first child
+        sme.start_with(&Messages::Tick);
+        assert_eq!(
+            take_events(),
+            vec![
+                "+base",
+                "+device",
+                "+connection",
+                "+disconnected",
+                "+power",
+                "+off"
+            ]
+        );
+        assert_eq!(sme.get_active_leaf_names(), vec!["disconnected", "off"]);
+
+        // Both regions handle Tick so device doesn't process it
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(sme.get_state_process_cnt(IDX_DISCONNECTED), 1);
+        assert_eq!(sme.get_state_process_cnt(IDX_OFF), 1);
+        assert_eq!(sme.get_state_process_cnt(IDX_DEVICE), 0);
+
+        // A transition in one region doesn't change the other, the
+        // states are entered when the next message is dispatched
+        sme.dispatch(&Messages::Connect).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(take_events(), vec!["-disconnected", "+connected"]);
+        sme.dispatch(&Messages::PowerOn).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(take_events(), vec!["-off", "+on"]);
+        assert_eq!(sme.get_active_leaf_names(), vec!["connected", "on"]);
+        assert_eq!(sme.get_current_state_name(), "on");
+
+        // Neither region handles Fail so device does, the regions
+        // are exited in the reverse order they were entered
+        sme.dispatch(&Messages::Fail).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(
+            take_events(),
+            vec![
+                "-on",
+                "-power",
+                "-connected",
+                "-connection",
+                "-device",
+                "+failed"
+            ]
+        );
+        assert_eq!(sme.get_active_leaf_names(), vec!["failed"]);
+
+        // Entering on enters the connection region at its first child
+        sme.dispatch(&Messages::Reset).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(
+            take_events(),
+            vec![
+                "-failed",
+                "+device",
+                "+connection",
+                "+disconnected",
+                "+power",
+                "+on"
+            ]
+        );
+        assert_eq!(sme.get_active_leaf_names(), vec!["disconnected", "on"]);
+
+        sme.stop_with(&Messages::Tick);
+        assert_eq!(
+            take_events(),
+            vec![
+                "-on",
+                "-power",
+                "-disconnected",
+                "-connection",
+                "-device",
+                "-base"
+            ]
+        );
+        assert!(sme.get_active_leaf_names().is_empty());
+
+        let result = Executor::new(RefCell::new(StateMachine), MAX_STATES)
+            .state(StateInfo::new("device", StateMachine::device).parallel())
+            .state(StateInfo::new("connection", StateMachine::not_handled).parent_idx(0))
+            .build(1);
+        assert_eq!(
+            result.err(),
+            Some(BuildError::TooFewRegions {
+                state: "device".to_owned(),
+                regions: 1,
+            })
+        );
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_history() {
+        //               base
+        //             /      \
+        //        player       menu
+        //      /  /  \  \
+        //     H  H*  stopped  playing
+        //                     /    \
+        //                  normal  fast
+
+        #[derive(Debug, Default)]
+        struct StateMachine;
+
+        #[derive(Debug)]
+        enum Messages {
+            Tick,
+            Play,
+            Fast,
+            Menu,
+            Back,
+            BackDeep,
+        }
+
+        // Records the enter and exit events
+        struct Events(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl Observer<Messages> for Events {
+            fn on_enter(&mut self, state: StateRef, _msg: &Messages) {
+                self.0.lock().unwrap().push(format!("+{}", state.name));
+            }
+
+            fn on_exit(&mut self, state: StateRef, _msg: &Messages) {
+                self.0.lock().unwrap().push(format!("-{}", state.name));
+            }
+        }
+
+        const MAX_STATES: usize = 9;
+        const IDX_BASE: usize = 0;
+        const IDX_PLAYER: usize = 1;
+        const IDX_HISTORY: usize = 2;
+        const IDX_DEEP_HISTORY: usize = 3;
+        const IDX_STOPPED: usize = 4;
+        const IDX_PLAYING: usize = 5;
+        const IDX_NORMAL: usize = 6;
+        const IDX_FAST: usize = 7;
+        const IDX_MENU: usize = 8;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::Yes, None)
+            }
+
+            #[no_coverage]
+            fn player(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Menu => (Handled::Yes, Some(IDX_MENU)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn stopped(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Play => (Handled::Yes, Some(IDX_NORMAL)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn playing(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn normal(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Fast => (Handled::Yes, Some(IDX_FAST)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn fast(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn menu(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Back => (Handled::Yes, Some(IDX_HISTORY)),
+                    Messages::BackDeep => (Handled::Yes, Some(IDX_DEEP_HISTORY)),
+                    _ => (Handled::No, None),
+                }
+            }
+        }
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sme = Executor::new(RefCell::new(StateMachine), MAX_STATES)
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(StateInfo::new("player", StateMachine::player).parent_idx(IDX_BASE))
+            .state(StateInfo::new_history("H", History::Shallow).parent_idx(IDX_PLAYER))
+            .state(StateInfo::new_history("H*", History::Deep).parent_idx(IDX_PLAYER))
+            .state(StateInfo::new("stopped", StateMachine::stopped).parent_idx(IDX_PLAYER))
+            .state(StateInfo::new("playing", StateMachine::playing).parent_idx(IDX_PLAYER))
+            .state(StateInfo::new("normal", StateMachine::normal).parent_idx(IDX_PLAYING))
+            .state(StateInfo::new("fast", StateMachine::fast).parent_idx(IDX_PLAYING))
+            .state(StateInfo::new("menu", StateMachine::menu).parent_idx(IDX_BASE))
+            .observer(Events(events.clone()))
+            .build(IDX_MENU)
+            .unwrap();
+        let take_events = || events.lock().unwrap().drain(..).collect::<Vec<String>>();
+        sme.start_with(&Messages::Tick);
+        take_events();
+
+        // player wasn't exited yet so its history is its first child
+        sme.dispatch(&Messages::Back).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(take_events(), vec!["-menu", "+player", "+stopped"]);
+        assert_eq!(sme.idx_current_state, IDX_STOPPED);
+
+        sme.dispatch(&Messages::Play).unwrap();
+        sme.dispatch(&Messages::Fast).unwrap();
+        sme.dispatch(&Messages::Menu).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(
+            take_events(),
+            vec![
+                "-stopped", "+playing", "+normal", "-normal", "+fast", "-fast", "-playing",
+                "-player", "+menu"
+            ]
+        );
+
+        // Shallow history enters playing at its first child
+        sme.dispatch(&Messages::Back).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(
+            take_events(),
+            vec!["-menu", "+player", "+playing", "+normal"]
+        );
+        assert_eq!(sme.get_current_state_name(), "normal");
+
+        // Deep history enters playing at fast
+        sme.dispatch(&Messages::Fast).unwrap();
+        sme.dispatch(&Messages::Menu).unwrap();
+        sme.dispatch(&Messages::BackDeep).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(
+            take_events(),
+            vec![
+                "-normal", "+fast", "-fast", "-playing", "-player", "+menu", "-menu", "+player",
+                "+playing", "+fast"
+            ]
+        );
+        assert_eq!(sme.get_current_state_name(), "fast");
+        assert_eq!(sme.get_state_enter_cnt(IDX_HISTORY), 0);
+
+        // A history state must have a parent
+        let result = Executor::<_, Messages>::new(RefCell::new(StateMachine), 1)
+            .state(StateInfo::new_history("H", History::Deep))
+            .build(0);
+        assert_eq!(
+            result.err(),
+            Some(BuildError::InvalidHistory {
+                state: "H".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_initial_child() {
+        //            base
+        //          /      \
+        //      idle        active
+        //                 /      \
+        //           starting    running
+        //                       /    \
+        //                    slow    fast
+
+        #[derive(Debug, Default)]
+        struct StateMachine;
+
+        #[derive(Debug)]
+        enum Messages {
+            Tick,
+            Start,
+            Stop,
+        }
+
+        const MAX_STATES: usize = 7;
+        const IDX_BASE: usize = 0;
+        const IDX_IDLE: usize = 1;
+        const IDX_ACTIVE: usize = 2;
+        const IDX_STARTING: usize = 3;
+        const IDX_RUNNING: usize = 4;
+        const IDX_SLOW: usize = 5;
+        const IDX_FAST: usize = 6;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn not_handled(
+                &mut self,
+                _e: &Executor<Self, Messages>,
+                _msg: &Messages,
+            ) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn idle(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Start => (Handled::Yes, Some(IDX_ACTIVE)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn active(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Stop => (Handled::Yes, Some(IDX_IDLE)),
+                    _ => (Handled::No, None),
+                }
+            }
+        }
+
+        let builder = |idx_initial_child| {
+            Executor::new(RefCell::new(StateMachine), MAX_STATES)
+                .state(StateInfo::new("base", StateMachine::not_handled))
+                .state(StateInfo::new("idle", StateMachine::idle).parent_idx(IDX_BASE))
+                .state(
+                    StateInfo::new("active", StateMachine::active)
+                        .parent_idx(IDX_BASE)
+                        .initial_child(idx_initial_child),
+                )
+                .state(StateInfo::new("starting", StateMachine::not_handled).parent_idx(IDX_ACTIVE))
+                .state(
+                    StateInfo::new("running", StateMachine::not_handled)
+                        .parent_idx(IDX_ACTIVE)
+                        .initial_child(IDX_FAST),
+                )
+                .state(StateInfo::new("slow", StateMachine::not_handled).parent_idx(IDX_RUNNING))
+                .state(StateInfo::new("fast", StateMachine::not_handled).parent_idx(IDX_RUNNING))
+        };
+
+        // A transition to active drills down to fast
+        let mut sme = builder(IDX_RUNNING).build(IDX_IDLE).unwrap();
+        sme.dispatch(&Messages::Start).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(sme.get_current_state_name(), "fast");
+        assert_eq!(sme.get_active_leaf_names(), vec!["fast"]);
+        sme.dispatch(&Messages::Stop).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(sme.get_active_leaf_names(), vec!["idle"]);
+
+        // The initial state may be a state with an initial child
+        let sme = builder(IDX_STARTING).build(IDX_ACTIVE).unwrap();
+        assert_eq!(sme.get_current_state_name(), "starting");
+
+        // The initial child must be a child
+        assert_eq!(
+            builder(IDX_SLOW).build(IDX_IDLE).err(),
+            Some(BuildError::InvalidInitialChild {
+                state: "active".to_owned(),
+                idx_child: IDX_SLOW,
+            })
+        );
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_transition_action() {
+        //       base
+        //      /    \
+        //  first    second
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            log: Vec<&'static str>,
+        }
+
+        #[derive(Debug)]
+        enum Messages {
+            Tick,
+            Stay,
+            Go,
+        }
+
+        const MAX_STATES: usize = 3;
+        const IDX_BASE: usize = 0;
+        const IDX_FIRST: usize = 1;
+        const IDX_SECOND: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::Yes, None)
+            }
+
+            #[no_coverage]
+            fn first(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Stay => {
+                        e.transition_action(Self::ignored);
+                        (Handled::Yes, None)
+                    }
+                    Messages::Go => {
+                        e.transition_action(Self::action);
+                        (Handled::Yes, Some(IDX_SECOND))
+                    }
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn first_exit(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
+                self.log.push("exit first");
+            }
+
+            #[no_coverage]
+            fn action(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
+                self.log.push("action");
+            }
+
+            #[no_coverage]
+            fn ignored(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
+                self.log.push("ignored");
+            }
+
+            #[no_coverage]
+            fn second_enter(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
+                self.log.push("enter second");
+            }
+
+            #[no_coverage]
+            fn second(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::No, None)
+            }
+        }
+
+        let mut sme = Executor::new(RefCell::new(StateMachine::default()), MAX_STATES)
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(
+                StateInfo::new("first", StateMachine::first)
+                    .parent_idx(IDX_BASE)
+                    .exit_fn(StateMachine::first_exit),
+            )
+            .state(
+                StateInfo::new("second", StateMachine::second)
+                    .parent_idx(IDX_BASE)
+                    .enter_fn(StateMachine::second_enter),
+            )
+            .build(IDX_FIRST)
+            .unwrap();
+
+        // Without a transition the action is ignored
+        sme.dispatch(&Messages::Stay).unwrap();
+        assert!(sme.get_sm().borrow().log.is_empty());
+
+        // The action runs between the exit and enter fns
+        sme.dispatch(&Messages::Go).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(
+            sme.get_sm().borrow().log,
+            vec!["exit first", "action", "enter second"]
+        );
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_transition_table() {
+        //     base
+        //    /    \
+        //  off    on
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            allowed: bool,
+            pings: usize,
+            log: Vec<&'static str>,
+        }
+
+        #[derive(Debug)]
+        enum Messages {
+            Tick,
+            Ping,
+            Power,
+        }
+
+        const MAX_STATES: usize = 3;
+        const IDX_BASE: usize = 0;
+        const IDX_OFF: usize = 1;
+        const IDX_ON: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn on(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Power => (Handled::Yes, Some(IDX_OFF)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn allowed(&self, _msg: &Messages) -> bool {
+                self.allowed
+            }
+
+            #[no_coverage]
+            fn ping(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
+                self.pings += 1;
+            }
+
+            #[no_coverage]
+            fn power_on(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
+                self.log.push("power on");
+            }
+
+            #[no_coverage]
+            fn off_exit(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
+                self.log.push("exit off");
+            }
+        }
+
+        let builder = |idx_target| {
+            Executor::new(RefCell::new(StateMachine::default()), MAX_STATES)
+                .state(StateInfo::new("base", StateMachine::base))
+                .state(
+                    StateInfo::new_table("off")
+                        .parent_idx(IDX_BASE)
+                        .exit_fn(StateMachine::off_exit)
+                        .transition(
+                            TransitionRule::new("Power", |msg| matches!(msg, Messages::Power))
+                                .guard(StateMachine::allowed)
+                                .action(StateMachine::power_on)
+                                .target(idx_target),
+                        )
+                        .transition(
+                            TransitionRule::new("Ping", |msg| matches!(msg, Messages::Ping))
+                                .action(StateMachine::ping),
+                        ),
+                )
+                .state(
+                    StateInfo::new("on", StateMachine::on)
+                        .parent_idx(IDX_BASE)
+                        .transition(TransitionRule::new("Ping", |msg| {
+                            matches!(msg, Messages::Ping)
+                        })),
+                )
+        };
+        let mut sme = builder(IDX_ON).build(IDX_OFF).unwrap();
+
+        // An internal rule runs its action immediately
+        sme.dispatch(&Messages::Ping).unwrap();
+        assert_eq!(sme.get_sm().borrow().pings, 1);
+
+        // The guard fails so no rule matches and base processes Power
+        sme.dispatch(&Messages::Power).unwrap();
+        assert_eq!(sme.get_current_state_name(), "off");
+        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 1);
+
+        // The action is the transition action
+        sme.get_sm().borrow_mut().allowed = true;
+        sme.dispatch(&Messages::Power).unwrap();
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(sme.get_current_state_name(), "on");
+        assert_eq!(sme.get_sm().borrow().log, vec!["exit off", "power on"]);
+
+        // A rule is evaluated before the process fn
+        sme.dispatch(&Messages::Ping).unwrap();
+        assert_eq!(sme.get_sm().borrow().pings, 1);
+        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 2);
+        sme.dispatch(&Messages::Power).unwrap();
+        assert_eq!(sme.get_current_state_name(), "off");
+
+        // Rules with a target are drawn
+        assert!(sme.to_mermaid().contains("    s1 --> s2 : Power\n"));
+        assert!(!sme.to_mermaid().contains("Ping"));
+
+        // The targets are validated
+        assert_eq!(
+            builder(IDX_BASE).build(IDX_OFF).err(),
+            Some(BuildError::InvalidRuleTarget {
+                state: "off".to_owned(),
+                label: "Power".to_owned(),
+                idx_target: IDX_BASE,
+            })
+        );
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_transition_kinds() {
+        //   base
+        //    |
+        //  outer
+        //    |
+        //   leaf
+
+        #[derive(Debug, Default)]
+        struct StateMachine;
+
+        #[derive(Debug)]
+        enum Messages {
+            Tick,
+            SelfExternal,
+            SelfInternal,
+            OuterExternal,
+            OuterInternal,
+        }
+
+        // Records the enter and exit events
+        struct Events(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl Observer<Messages> for Events {
+            fn on_enter(&mut self, state: StateRef, _msg: &Messages) {
+                self.0.lock().unwrap().push(format!("+{}", state.name));
+            }
+
+            fn on_exit(&mut self, state: StateRef, _msg: &Messages) {
+                self.0.lock().unwrap().push(format!("-{}", state.name));
+            }
+        }
+
+        const MAX_STATES: usize = 3;
+        const IDX_BASE: usize = 0;
+        const IDX_OUTER: usize = 1;
+        const IDX_LEAF: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn not_handled(
+                &mut self,
+                _e: &Executor<Self, Messages>,
+                _msg: &Messages,
+            ) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn leaf(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Tick => (Handled::Yes, None),
+                    Messages::SelfExternal => (Handled::Yes, Some(IDX_LEAF)),
+                    Messages::SelfInternal => {
+                        e.transition_kind(TransitionKind::Internal);
+                        (Handled::Yes, Some(IDX_LEAF))
+                    }
+                    Messages::OuterExternal => (Handled::Yes, Some(IDX_OUTER)),
+                    Messages::OuterInternal => {
+                        e.transition_kind(TransitionKind::Internal);
+                        (Handled::Yes, Some(IDX_OUTER))
+                    }
+                }
+            }
+        }
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sme = Executor::new(RefCell::new(StateMachine), MAX_STATES)
+            .state(StateInfo::new("base", StateMachine::not_handled))
+            .state(
+                StateInfo::new("outer", StateMachine::not_handled)
+                    .parent_idx(IDX_BASE)
+                    .initial_child(IDX_LEAF),
+            )
+            .state(StateInfo::new("leaf", StateMachine::leaf).parent_idx(IDX_OUTER))
+            .observer(Events(events.clone()))
+            .build(IDX_LEAF)
+            .unwrap();
+        let take_events = || events.lock().unwrap().drain(..).collect::<Vec<String>>();
+        sme.start_with(&Messages::Tick);
+        assert_eq!(take_events(), vec!["+base", "+outer", "+leaf"]);
+
+        // An external self transition exits and enters the state
+        assert!(sme.dispatch(&Messages::SelfExternal).unwrap());
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(take_events(), vec!["-leaf", "+leaf"]);
+
+        // An internal self transition exits and enters nothing
+        assert!(!sme.dispatch(&Messages::SelfInternal).unwrap());
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert!(take_events().is_empty());
+
+        // An external transition to an ancestor exits and enters it
+        assert!(sme.dispatch(&Messages::OuterExternal).unwrap());
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert_eq!(take_events(), vec!["-leaf", "-outer", "+outer", "+leaf"]);
+
+        // An internal transition to an ancestor doesn't
+        assert!(!sme.dispatch(&Messages::OuterInternal).unwrap());
+        sme.dispatch(&Messages::Tick).unwrap();
+        assert!(take_events().is_empty());
+        assert_eq!(sme.get_current_state_name(), "leaf");
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_final_states() {
+        //             base
+        //       /      |         \
+        //    job      both        end
+        //   /   \      |   \
+        // work  finished  a    b
+        //                /  \   /  \
+        //           a_busy a_done b_busy b_done
+
+        #[derive(Debug, Default)]
+        struct StateMachine;
+
+        #[derive(Debug)]
+        enum Messages {
+            Tick,
+            Finish,
+            A,
+            B,
+        }
+
+        // Records the enter, exit and completion events
+        struct Events(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl Observer<Messages> for Events {
+            fn on_enter(&mut self, state: StateRef, _msg: &Messages) {
+                self.0.lock().unwrap().push(format!("+{}", state.name));
+            }
+
+            fn on_exit(&mut self, state: StateRef, _msg: &Messages) {
+                self.0.lock().unwrap().push(format!("-{}", state.name));
+            }
+
+            fn on_completion(&mut self, state: StateRef, _msg: &Messages) {
+                self.0.lock().unwrap().push(format!("!{}", state.name));
+            }
+        }
+
+        const MAX_STATES: usize = 12;
+        const IDX_BASE: usize = 0;
+        const IDX_JOB: usize = 1;
+        const IDX_WORK: usize = 2;
+        const IDX_FINISHED: usize = 3;
+        const IDX_BOTH: usize = 4;
+        const IDX_A: usize = 5;
+        const IDX_A_BUSY: usize = 6;
+        const IDX_A_DONE: usize = 7;
+        const IDX_B: usize = 8;
+        const IDX_B_BUSY: usize = 9;
+        const IDX_B_DONE: usize = 10;
+        const IDX_END: usize = 11;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn not_handled(
+                &mut self,
+                _e: &Executor<Self, Messages>,
+                _msg: &Messages,
+            ) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn work(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Finish => (Handled::Yes, Some(IDX_FINISHED)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn a_busy(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::A => (Handled::Yes, Some(IDX_A_DONE)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn b_busy(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::B => (Handled::Yes, Some(IDX_B_DONE)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn job_completed(
+                &mut self,
+                _e: &Executor<Self, Messages>,
+                _msg: &Messages,
+            ) -> StateResult {
+                (Handled::Yes, Some(IDX_A_BUSY))
+            }
+
+            #[no_coverage]
+            fn both_completed(
+                &mut self,
+                _e: &Executor<Self, Messages>,
+                _msg: &Messages,
+            ) -> StateResult {
+                (Handled::Yes, Some(IDX_END))
+            }
+        }
+
+        let builder = || {
+            Executor::new(RefCell::new(StateMachine), MAX_STATES)
+                .state(StateInfo::new("base", StateMachine::not_handled))
+                .state(
+                    StateInfo::new("job", StateMachine::not_handled)
+                        .parent_idx(IDX_BASE)
+                        .completion_fn(StateMachine::job_completed),
+                )
+                .state(StateInfo::new("work", StateMachine::work).parent_idx(IDX_JOB))
+                .state(StateInfo::new_final("finished").parent_idx(IDX_JOB))
+                .state(
+                    StateInfo::new("both", StateMachine::not_handled)
+                        .parent_idx(IDX_BASE)
+                        .parallel()
+                        .completion_fn(StateMachine::both_completed),
+                )
+                .state(StateInfo::new("a", StateMachine::not_handled).parent_idx(IDX_BOTH))
+                .state(StateInfo::new("a_busy", StateMachine::a_busy).parent_idx(IDX_A))
+                .state(StateInfo::new_final("a_done").parent_idx(IDX_A))
+                .state(StateInfo::new("b", StateMachine::not_handled).parent_idx(IDX_BOTH))
+                .state(StateInfo::new("b_busy", StateMachine::b_busy).parent_idx(IDX_B))
+                .state(StateInfo::new_final("b_done").parent_idx(IDX_B))
+        };
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sme = builder()
+            .state(StateInfo::new_final("end").parent_idx(IDX_BASE))
+            .observer(Events(events.clone()))
+            .build(IDX_WORK)
+            .unwrap();
+        let take_events = || events.lock().unwrap().drain(..).collect::<Vec<String>>();
+        sme.start_with(&Messages::Tick);
+        assert_eq!(take_events(), vec!["+base", "+job", "+work"]);
+        assert_eq!(sme.status(), Status::Running);
+
+        // Entering finished completes job whose completion fn
+        // transitions into both, all in the same dispatch
+        assert_eq!(sme.dispatcher(&Messages::Finish), Ok(Status::Running));
+        assert_eq!(
+            take_events(),
+            vec![
+                "-work",
+                "+finished",
+                "!job",
+                "-finished",
+                "-job",
+                "+both",
+                "+a",
+                "+a_busy",
+                "+b",
+                "+b_busy"
+            ]
+        );
+
+        // A region is completed but both isn't until all of them are
+        assert_eq!(sme.dispatcher(&Messages::A), Ok(Status::Running));
+        assert_eq!(take_events(), vec!["-a_busy", "+a_done", "!a"]);
+        assert_eq!(sme.get_active_leaf_names(), vec!["a_done", "b_busy"]);
+        assert_eq!(sme.get_state_process_cnt(IDX_B_BUSY), 1);
+
+        // Completing both transitions to end, a final child of
+        // the root, so the machine terminates
+        assert_eq!(sme.dispatcher(&Messages::B), Ok(Status::Terminated));
+        assert_eq!(
+            take_events(),
+            vec![
+                "-b_busy", "+b_done", "!b", "!both", "-b_done", "-b", "-a_done", "-a", "-both",
+                "+end", "!base"
+            ]
+        );
+        assert_eq!(sme.get_current_state_name(), "end");
+        assert_eq!(sme.status(), Status::Terminated);
+        assert_eq!(sme.idx_current_state, IDX_END);
+
+        // A final state can't have children
+        assert_eq!(
+            builder()
+                .state(StateInfo::new("end", StateMachine::not_handled).parent_idx(IDX_B_DONE))
+                .build(IDX_WORK)
+                .err(),
+            Some(BuildError::FinalWithChildren {
+                state: "b_done".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_unhandled() {
+        //     base
+        //    /    \
+        //  idle   busy
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            unhandled: Vec<String>,
+        }
+
+        #[derive(Debug)]
+        enum Messages {
+            Start,
+            Ping,
+            Pong,
+        }
+
+        const MAX_STATES: usize = 3;
+        const IDX_BASE: usize = 0;
+        const IDX_IDLE: usize = 1;
+        const IDX_BUSY: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn idle(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Start => (Handled::Yes, Some(IDX_BUSY)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn busy(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn unhandled(&mut self, e: &Executor<Self, Messages>, msg: &Messages) {
+                self.unhandled
+                    .push(format!("{} {msg:?}", e.get_current_state_name()));
+            }
+        }
+
+        let mut sme = Executor::new(RefCell::new(StateMachine::default()), MAX_STATES)
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(StateInfo::new("idle", StateMachine::idle).parent_idx(IDX_BASE))
+            .state(StateInfo::new("busy", StateMachine::busy).parent_idx(IDX_BASE))
+            .unhandled_fn(StateMachine::unhandled)
+            .dead_letter_capacity(2)
+            .build(IDX_IDLE)
+            .unwrap();
+
+        // Handled messages aren't dead letters
+        sme.dispatcher(&Messages::Start).unwrap();
+        assert_eq!(sme.unhandled_cnt(), 0);
+
+        // The oldest dead letters are dropped
+        for msg in [Messages::Ping, Messages::Pong, Messages::Ping] {
+            sme.dispatcher(&msg).unwrap();
+        }
+        sme.dispatch_idx(&Messages::Start, IDX_BUSY).unwrap();
+        assert_eq!(sme.unhandled_cnt(), 4);
+        assert_eq!(sme.dead_letters_dropped(), 2);
+        assert_eq!(
+            sme.get_sm().borrow().unhandled,
+            vec!["busy Ping", "busy Pong", "busy Ping", "busy Start",]
+        );
+        assert_eq!(
+            sme.take_dead_letters(),
+            vec![
+                DeadLetter {
+                    idx_state: IDX_BUSY,
+                    state: "busy".to_owned(),
+                    msg: "Ping".to_owned(),
+                },
+                DeadLetter {
+                    idx_state: IDX_BUSY,
+                    state: "busy".to_owned(),
+                    msg: "Start".to_owned(),
+                },
+            ]
+        );
+        assert!(sme.take_dead_letters().is_empty());
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_defer_limits() {
+        //        base
+        //     /   |    \
+        //  busy  idle  error
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            rejected: Vec<u8>,
+            processed: Vec<u8>,
+        }
+
+        #[derive(Debug)]
+        enum Messages {
+            Val(u8),
+            Toggle,
+            Again,
+        }
+
+        const MAX_STATES: usize = 4;
+        const IDX_BASE: usize = 0;
+        const IDX_BUSY: usize = 1;
+        const IDX_IDLE: usize = 2;
+        const IDX_ERROR: usize = 3;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::Yes, None)
+            }
+
+            // Defers the Vals
+            #[no_coverage]
+            fn busy(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Val(val) => {
+                        if let Err(SendError(Messages::Val(val))) =
+                            e.defer_current(Messages::Val(*val))
+                        {
+                            self.rejected.push(val);
+                        }
+                        (Handled::Yes, None)
+                    }
+                    Messages::Toggle => (Handled::Yes, Some(IDX_IDLE)),
+                    Messages::Again => (Handled::Yes, Some(IDX_BUSY)),
+                }
+            }
+
+            #[no_coverage]
+            fn idle(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Val(val) => {
+                        self.processed.push(*val);
+                        (Handled::Yes, None)
+                    }
+                    Messages::Toggle => (Handled::Yes, Some(IDX_BUSY)),
+                    Messages::Again => (Handled::Yes, None),
+                }
+            }
+        }
+
+        let builder = |policy| {
+            Executor::new(RefCell::new(StateMachine::default()), MAX_STATES)
+                .state(StateInfo::new("base", StateMachine::base))
+                .state(StateInfo::new("busy", StateMachine::busy).parent_idx(IDX_BASE))
+                .state(StateInfo::new("idle", StateMachine::idle).parent_idx(IDX_BASE))
+                .state(StateInfo::new("error", StateMachine::base).parent_idx(IDX_BASE))
+                .defer_capacity(2)
+                .defer_overflow_policy(policy)
+        };
+        let run = |sme: &mut Executor<StateMachine, Messages>| {
+            for val in 1..=3 {
+                sme.dispatcher(&Messages::Val(val)).unwrap();
+            }
+            assert_eq!(sme.defer_depth(), 2);
+            assert_eq!(sme.defer_overflows(), 1);
+            sme.dispatcher(&Messages::Toggle).unwrap();
+        };
+
+        // The third Val is rejected
+        let mut sme = builder(DeferOverflowPolicy::Reject)
+            .build(IDX_BUSY)
+            .unwrap();
+        run(&mut sme);
+        assert_eq!(sme.get_sm().borrow().rejected, vec![3]);
+        assert_eq!(sme.get_sm().borrow().processed, vec![1, 2]);
+        assert_eq!(sme.defer_depth(), 0);
+        assert_eq!(sme.defer_dropped(), 0);
+
+        let mut sme = builder(DeferOverflowPolicy::DropOldest)
+            .build(IDX_BUSY)
+            .unwrap();
+        run(&mut sme);
+        assert_eq!(sme.get_sm().borrow().processed, vec![2, 3]);
+        assert_eq!(sme.defer_dropped(), 1);
+
+        let mut sme = builder(DeferOverflowPolicy::DropNewest)
+            .build(IDX_BUSY)
+            .unwrap();
+        run(&mut sme);
+        assert_eq!(sme.get_sm().borrow().processed, vec![1, 2]);
+        assert_eq!(sme.defer_dropped(), 1);
+
+        // The overflow transitions to error and the deferred Vals
+        // are replayed there
+        let mut sme = builder(DeferOverflowPolicy::ErrorState(IDX_ERROR))
+            .build(IDX_BUSY)
+            .unwrap();
+        for val in 1..=3 {
+            sme.dispatcher(&Messages::Val(val)).unwrap();
+        }
+        assert_eq!(sme.get_current_state_name(), "error");
+        assert_eq!(sme.defer_depth(), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_ERROR), 2);
+        assert_eq!(sme.defer_dropped(), 1);
+
+        // Outside of a dispatch the overflow only drops the Val
+        let mut sme = builder(DeferOverflowPolicy::ErrorState(IDX_ERROR))
+            .build(IDX_BUSY)
+            .unwrap();
+        for val in 1..=3 {
+            sme.defer_send(Messages::Val(val)).unwrap();
+        }
+        assert_eq!(sme.defer_dropped(), 1);
+        sme.dispatcher(&Messages::Again).unwrap();
+        assert_eq!(sme.get_current_state_name(), "busy");
+        assert_eq!(sme.defer_depth(), 2);
+        assert!(matches!(
+            builder(DeferOverflowPolicy::ErrorState(IDX_BASE)).build(IDX_BUSY),
+            Err(BuildError::InvalidErrorState {
+                idx_error_state: IDX_BASE,
+                ..
+            })
+        ));
+
+        // Val 1 is replayed in busy and deferred again so it expires,
+        // Val 2 expires as it was deferred 10s ago and Val 3 doesn't
+        let clock = ManualClock::new();
+        let mut sme = builder(DeferOverflowPolicy::Reject)
+            .defer_max_replays(1)
+            .defer_max_age(Duration::from_secs(10))
+            .clock(Arc::new(clock.clone()))
+            .build(IDX_BUSY)
+            .unwrap();
+        sme.dispatcher(&Messages::Val(1)).unwrap();
+        sme.dispatcher(&Messages::Again).unwrap();
+        sme.dispatcher(&Messages::Toggle).unwrap();
+        assert_eq!(sme.defer_expired(), 1);
+        sme.dispatcher(&Messages::Toggle).unwrap();
+        sme.dispatcher(&Messages::Val(2)).unwrap();
+        clock.advance(Duration::from_secs(9));
+        sme.dispatcher(&Messages::Val(3)).unwrap();
+        clock.advance(Duration::from_secs(1));
+        sme.dispatcher(&Messages::Toggle).unwrap();
+        assert_eq!(sme.defer_expired(), 2);
+        assert_eq!(sme.get_sm().borrow().processed, vec![3]);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_declarative_defer() {
+        //        base
+        //       /    \
+        //    idle   working
+        //           /     \
+        //        step1   step2
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            processed: Vec<u8>,
+        }
+
+        #[derive(Debug, Clone)]
+        enum Messages {
+            Work(u8),
+            Next,
+            Done,
+        }
+
+        const MAX_STATES: usize = 5;
+        const IDX_BASE: usize = 0;
+        const IDX_IDLE: usize = 1;
+        const IDX_WORKING: usize = 2;
+        const IDX_STEP1: usize = 3;
+        const IDX_STEP2: usize = 4;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn not_handled(
+                &mut self,
+                _e: &Executor<Self, Messages>,
+                _msg: &Messages,
+            ) -> StateResult {
+                (Handled::No, None)
+            }
+
+            #[no_coverage]
+            fn idle(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Work(val) => {
+                        self.processed.push(*val);
+                        (Handled::Yes, None)
+                    }
+                    Messages::Next => (Handled::Yes, Some(IDX_STEP1)),
+                    Messages::Done => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn step1(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    // Never seen, working defers Work before step1 runs
+                    Messages::Work(val) => {
+                        self.processed.push(*val + 100);
+                        (Handled::Yes, None)
+                    }
+                    Messages::Next => (Handled::Yes, Some(IDX_STEP2)),
+                    _ => (Handled::No, None),
+                }
+            }
+
+            #[no_coverage]
+            fn step2(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Done => (Handled::Yes, Some(IDX_IDLE)),
+                    _ => (Handled::No, None),
+                }
+            }
+        }
+
+        let mut sme = Executor::new(RefCell::new(StateMachine::default()), MAX_STATES)
+            .state(StateInfo::new("base", StateMachine::not_handled))
+            .state(StateInfo::new("idle", StateMachine::idle).parent_idx(IDX_BASE))
+            .state(
+                StateInfo::new("working", StateMachine::not_handled)
+                    .parent_idx(IDX_BASE)
+                    .defer(|msg| matches!(msg, Messages::Work(_))),
+            )
+            .state(StateInfo::new("step1", StateMachine::step1).parent_idx(IDX_WORKING))
+            .state(StateInfo::new("step2", StateMachine::step2).parent_idx(IDX_WORKING))
+            .defer_capacity(2)
+            .build(IDX_IDLE)
+            .unwrap();
+
+        // The Works are deferred while working is active, even though
+        // step1 handles them, the third is rejected so it's dropped
+        // rather than processed
+        sme.dispatcher(&Messages::Next).unwrap();
+        assert_eq!(sme.get_current_state_name(), "step1");
+        for val in 1..=3 {
+            sme.dispatcher(&Messages::Work(val)).unwrap();
+        }
+        assert!(sme.get_sm().borrow().processed.is_empty());
+        assert_eq!(sme.defer_depth(), 2);
+        assert_eq!(sme.defer_overflows(), 1);
+        assert_eq!(sme.defer_dropped(), 1);
+        assert_eq!(sme.get_state_process_cnt(IDX_WORKING), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STEP1), 0);
+        assert_eq!(sme.unhandled_cnt(), 0);
+
+        // working is still active so they aren't replayed in step2
+        sme.dispatcher(&Messages::Next).unwrap();
+        sme.dispatcher(&Messages::Done).unwrap();
+        assert_eq!(sme.get_state_process_cnt(IDX_STEP2), 1);
+
+        // But are once idle is entered
+        assert_eq!(sme.get_current_state_name(), "idle");
+        assert_eq!(sme.get_sm().borrow().processed, vec![1, 2]);
+        assert_eq!(sme.defer_depth(), 0);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_deferred_order() {
+        use std::sync::Mutex;
+
+        //     base
+        //    /    \
+        //  busy   idle
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            // val, seq and enqueued_at of the processed Works
+            processed: Vec<(u8, u64, Instant)>,
+        }
+
+        #[derive(Debug, Clone)]
+        enum Messages {
+            Work(u8),
+            Again,
+            Toggle,
+        }
+
+        // The seqs passed to on_dispatch
+        struct Seqs(Arc<Mutex<Vec<u64>>>);
+
+        impl Observer<Messages> for Seqs {
+            fn on_dispatch(&mut self, info: MsgInfo, _msg: &Messages) {
+                self.0.lock().unwrap().push(info.seq);
+            }
+        }
+
+        const MAX_STATES: usize = 3;
+        const IDX_BASE: usize = 0;
+        const IDX_BUSY: usize = 1;
+        const IDX_IDLE: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                (Handled::Yes, None)
+            }
+
+            #[no_coverage]
+            fn busy(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    // Only seen if busy didn't defer Work
+                    Messages::Work(_) => (Handled::No, None),
+                    Messages::Again => (Handled::Yes, Some(IDX_BUSY)),
+                    Messages::Toggle => (Handled::Yes, Some(IDX_IDLE)),
+                }
+            }
+
+            #[no_coverage]
+            fn idle(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    // An invalid transition, see InvalidTransitionPolicy::ReturnError
+                    Messages::Work(0) => (Handled::Yes, Some(MAX_STATES)),
+                    Messages::Work(val) => {
+                        if *val == 7 {
+                            e.defer_send(Messages::Work(8)).unwrap();
+                        }
+                        let info = e.current_msg_info().unwrap();
+                        self.processed.push((*val, info.seq, info.enqueued_at));
+                        (Handled::Yes, None)
+                    }
+                    Messages::Again => {
+                        e.defer_send(Messages::Work(9)).unwrap();
+                        (Handled::Yes, Some(IDX_BUSY))
+                    }
+                    Messages::Toggle => (Handled::No, None),
+                }
+            }
+        }
+
+        let clock = ManualClock::new();
+        let t0 = clock.now();
+        let seqs = Arc::new(Mutex::new(Vec::new()));
+        let mut sme = Executor::new(RefCell::new(StateMachine::default()), MAX_STATES)
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(
+                StateInfo::new("busy", StateMachine::busy)
+                    .parent_idx(IDX_BASE)
+                    .defer(|msg| matches!(msg, Messages::Work(_))),
+            )
+            .state(StateInfo::new("idle", StateMachine::idle).parent_idx(IDX_BASE))
+            .clock(Arc::new(clock.clone()))
+            .observer(Seqs(seqs.clone()))
+            .invalid_transition_policy(InvalidTransitionPolicy::ReturnError)
+            .build(IDX_BUSY)
+            .unwrap();
+
+        // Works 1 and 2 are still deferred by busy after Again, Work 3
+        // is deferred after them and Work 4 is sent before Toggle
+        // transitions to idle
+        let sender = sme.clone_sender();
+        sender.send(Messages::Work(1)).unwrap();
+        sme.send(Messages::Work(2)).unwrap();
+        sme.send(Messages::Again).unwrap();
+        clock.advance(Duration::from_secs(1));
+        sender.send(Messages::Work(3)).unwrap();
+        sme.send(Messages::Toggle).unwrap();
+        sme.send(Messages::Work(4)).unwrap();
+        sender.stop();
+        sme.run().unwrap();
+        assert!(sme.current_msg_info().is_none());
+
+        // The deferred Works keep their seqs and are replayed in
+        // the order they were sent before the newer Work 4
+        let t1 = t0 + Duration::from_secs(1);
+        assert_eq!(
+            sme.get_sm().borrow().processed,
+            vec![(1, 0, t0), (2, 1, t0), (3, 3, t1), (4, 5, t1)]
+        );
+        assert_eq!(*seqs.lock().unwrap(), vec![0, 1, 2, 3, 4, 0, 1, 3, 5]);
+
+        // The dispatchers stamp the messages they're passed
+        sme.dispatcher(&Messages::Work(5)).unwrap();
+        assert_eq!(sme.get_sm().borrow().processed[4], (5, 6, t1));
+
+        // Work 9 is deferred by idle while dispatching Again, it's a
+        // new message so it's stamped after Toggle which was sent first
+        sme.send(Messages::Again).unwrap();
+        sme.send(Messages::Toggle).unwrap();
+        while let Ok((mut msg, info)) = sme.try_recv_with_info() {
+            sme.dispatcher_with_info(&mut msg, info).unwrap();
+        }
+        assert_eq!(sme.get_sm().borrow().processed[5], (9, 9, t1));
+        assert_eq!(seqs.lock().unwrap()[9..], [6, 7, 8, 9]);
+
+        // Replaying Work 0 fails, the Works deferred with it are still
+        // replayed before the error is returned so none are left to
+        // be replayed after newer deferred messages
+        sme.dispatcher(&Messages::Again).unwrap();
+        sme.dispatcher(&Messages::Work(0)).unwrap();
+        sme.dispatcher(&Messages::Work(6)).unwrap();
+        assert_eq!(sme.defer_depth(), 3);
+        assert!(matches!(
+            sme.dispatcher(&Messages::Toggle),
+            Err(DispatchError::InvalidTransition(_))
+        ));
+        assert_eq!(sme.defer_depth(), 0);
+        assert_eq!(sme.get_current_state_name(), "idle");
+        assert_eq!(
+            sme.get_sm().borrow().processed[6..],
+            [(9, 11, t1), (6, 13, t1)]
+        );
+        assert_eq!(seqs.lock().unwrap()[13..], [10, 12, 13, 14, 11, 12, 13]);
+
+        // Work 8 is deferred by idle while Work 7 is replayed, it's a
+        // new message so it doesn't get the seq of Work 7
+        sme.dispatcher(&Messages::Again).unwrap();
+        sme.dispatcher(&Messages::Work(7)).unwrap();
+        sme.dispatcher(&Messages::Toggle).unwrap();
+        sme.dispatcher(&Messages::Again).unwrap();
+        sme.dispatcher(&Messages::Toggle).unwrap();
+        assert_eq!(
+            sme.get_sm().borrow().processed[8..],
+            [(9, 16, t1), (7, 17, t1), (8, 19, t1), (9, 21, t1)]
+        );
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_drain_budget() {
+        // Counts down to zero by sending itself the next Count
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            counts: Vec<u8>,
+        }
+
+        #[derive(Debug, PartialEq)]
+        enum Messages {
+            Count(u8),
+        }
+
+        const MAX_STATES: usize = 1;
+        const IDX_COUNTER: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn counter(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                let Messages::Count(count) = msg;
+                self.counts.push(*count);
+                if *count > 0 {
+                    e.send(Messages::Count(count - 1)).unwrap();
+                }
+                (Handled::Yes, None)
+            }
+        }
+
+        let new = |budget| {
+            Executor::new(RefCell::new(StateMachine::default()), MAX_STATES)
+                .state(StateInfo::new("counter", StateMachine::counter))
+                .drain_budget(budget)
+                .build(IDX_COUNTER)
+                .unwrap()
+        };
+
+        // Drained to quiescence
+        let mut sme = new(10);
+        sme.dispatcher(&Messages::Count(3)).unwrap();
+        assert_eq!(sme.get_sm().borrow().counts, vec![3, 2, 1, 0]);
+        assert_eq!(sme.drain_exhausted(), 0);
+        assert!(sme.try_recv().is_err());
+
+        // The budget is exhausted leaving Count(2) queued
+        let mut sme = new(2);
+        sme.dispatcher(&Messages::Count(5)).unwrap();
+        assert_eq!(sme.get_sm().borrow().counts, vec![5, 4, 3]);
+        assert_eq!(sme.drain_exhausted(), 1);
+        assert_eq!(sme.try_recv(), Ok(Messages::Count(2)));
+
+        // A stop drained from the queue still stops run and the
+        // messages sent after it remain queued
+        let mut sme = new(10);
+        sme.send(Messages::Count(1)).unwrap();
+        sme.clone_sender().stop();
+        sme.dispatcher(&Messages::Count(0)).unwrap();
+        assert_eq!(sme.get_sm().borrow().counts, vec![0, 1]);
+        sme.run().unwrap();
+        assert_eq!(sme.try_recv(), Ok(Messages::Count(0)));
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_diagrams() {
+        #[derive(Debug, Default)]
+        struct StateMachine;
+
+        #[derive(Debug)]
+        struct NoMessages;
+
+        const MAX_STATES: usize = 3;
+        const IDX_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                (Handled::Yes, Some(IDX_OTHER))
+            }
+
+            #[no_coverage]
+            fn child(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                (Handled::No, None)
+            }
+        }
+
+        let mut sme = Executor::new(RefCell::new(StateMachine), MAX_STATES)
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(StateInfo::new("initial", StateMachine::child).parent_idx(IDX_BASE))
+            .state(StateInfo::new("other", StateMachine::child).parent_idx(IDX_BASE))
+            .build(IDX_INITIAL)
+            .unwrap();
+        let mermaid = sme.to_mermaid();
+        assert!(mermaid.contains("state s0 {\n        [*] --> s1\n"));
+        assert!(mermaid.ends_with("class s1 current\n"));
+
+        sme.dispatch(&NoMessages).unwrap();
+        assert!(sme.to_mermaid().ends_with("class s2 current\n"));
+        assert!(sme.to_dot().contains("initial -> s1;"));
+        assert!(sme
+            .to_plantuml()
+            .contains("state \"other\" as s2 #ffcc66\n"));
+    }
 }
@@ -36,7 +36,7 @@ impl StateMachine {
         println!("{}:+ &self={self:p}", e.get_state_name(IDX_STATE1));
 
         // Defer messages
-        e.defer_send(msg.clone());
+        e.defer_current(msg.clone());
 
         println!("{}:-", e.get_state_name(IDX_STATE1));
         (Handled::Yes, Some(IDX_STATE2))
@@ -78,7 +78,7 @@ fn main() {
 
     // msg.val == 1 will be deferred and processed in state2
     let msg = Messages::Val { val: 1 };
-    sme.dispatcher(&msg);
+    sme.dispatcher(&msg).unwrap();
     assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
     assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
     assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
@@ -93,7 +93,7 @@ fn main() {
 
     // msg.val == 2 will be deferred and processed in state2
     let msg = Messages::Val { val: 2 };
-    sme.dispatcher(&msg);
+    sme.dispatcher(&msg).unwrap();
     assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
     assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 2);
     assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
@@ -0,0 +1,289 @@
+use std::fmt::Debug;
+
+use crate::MsgInfo;
+
+// A state passed to an Observer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateRef<'a> {
+    pub idx: usize,
+    pub name: &'a str,
+}
+
+// Notified of what an Executor does, register with Executor::observer.
+//
+// All methods default to doing nothing so only the events of
+// interest need to be implemented.
+pub trait Observer<P> {
+    // msg is about to be dispatched, a replayed deferred msg has
+    // the info it was first accepted with
+    fn on_dispatch(&mut self, _info: MsgInfo, _msg: &P) {}
+
+    // A state was entered, called even if it has no enter fn
+    fn on_enter(&mut self, _state: StateRef, _msg: &P) {}
+
+    // The process fn of a state returned, handled is true for Handled::Yes
+    fn on_process(&mut self, _state: StateRef, _msg: &P, _handled: bool) {}
+
+    // A state was exited, called even if it has no exit fn
+    fn on_exit(&mut self, _state: StateRef, _msg: &P) {}
+
+    // src returned a transition to dest. The states from the current
+    // state up to but excluding ancestor will be exited and the states
+    // below ancestor down to dest entered. ancestor is None if the
+    // transition exits and enters all the way to the root.
+    fn on_transition(
+        &mut self,
+        _src: StateRef,
+        _dest: StateRef,
+        _ancestor: Option<StateRef>,
+        _msg: &P,
+    ) {
+    }
+
+    // No state handled msg, state is the current state
+    fn on_unhandled(&mut self, _state: StateRef, _msg: &P) {}
+
+    // A state was completed, its active child or the active children
+    // of all its regions are final states, see StateInfo::completion_fn
+    fn on_completion(&mut self, _state: StateRef, _msg: &P) {}
+
+    // msg was deferred by state, with Executor::defer_send,
+    // Executor::defer_current or StateInfo::defer
+    fn on_defer(&mut self, _state: StateRef, _msg: &P) {}
+
+    // The defer overflow policy dropped msg, either the msg being
+    // deferred or the oldest deferred msg, see DeferOverflowPolicy
+    fn on_defer_dropped(&mut self, _msg: &P) {}
+
+    // A deferred msg is about to be dispatched again
+    fn on_deferred_replay(&mut self, _msg: &P) {}
+
+    // A deferred msg was dropped instead of being dispatched again, see
+    // Executor::defer_max_replays and Executor::defer_max_age
+    fn on_defer_expired(&mut self, _msg: &P) {}
+}
+
+// Logs every event with log::trace!
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TraceObserver;
+
+impl<P: Debug> Observer<P> for TraceObserver {
+    fn on_dispatch(&mut self, info: MsgInfo, msg: &P) {
+        log::trace!("dispatch: seq={} msg={msg:?}", info.seq);
+    }
+
+    fn on_enter(&mut self, state: StateRef, msg: &P) {
+        log::trace!("enter: {} {} msg={msg:?}", state.idx, state.name);
+    }
+
+    fn on_process(&mut self, state: StateRef, msg: &P, handled: bool) {
+        log::trace!(
+            "process: {} {} msg={msg:?} handled={handled}",
+            state.idx,
+            state.name
+        );
+    }
+
+    fn on_exit(&mut self, state: StateRef, msg: &P) {
+        log::trace!("exit: {} {} msg={msg:?}", state.idx, state.name);
+    }
+
+    fn on_transition(
+        &mut self,
+        src: StateRef,
+        dest: StateRef,
+        ancestor: Option<StateRef>,
+        msg: &P,
+    ) {
+        log::trace!(
+            "transition: {} {} -> {} {} ancestor={:?} msg={msg:?}",
+            src.idx,
+            src.name,
+            dest.idx,
+            dest.name,
+            ancestor.map(|state| state.name)
+        );
+    }
+
+    fn on_unhandled(&mut self, state: StateRef, msg: &P) {
+        log::trace!("unhandled: {} {} msg={msg:?}", state.idx, state.name);
+    }
+
+    fn on_completion(&mut self, state: StateRef, msg: &P) {
+        log::trace!("completion: {} {} msg={msg:?}", state.idx, state.name);
+    }
+
+    fn on_defer(&mut self, state: StateRef, msg: &P) {
+        log::trace!("defer: {} {} msg={msg:?}", state.idx, state.name);
+    }
+
+    fn on_defer_dropped(&mut self, msg: &P) {
+        log::trace!("defer_dropped: msg={msg:?}");
+    }
+
+    fn on_deferred_replay(&mut self, msg: &P) {
+        log::trace!("deferred_replay: msg={msg:?}");
+    }
+
+    fn on_defer_expired(&mut self, msg: &P) {
+        log::trace!("defer_expired: msg={msg:?}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{DeferOverflowPolicy, Executor, Handled, StateInfo, StateResult};
+
+    // Records the events as strings
+    struct Recorder {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Observer<Messages> for Recorder {
+        fn on_enter(&mut self, state: StateRef, _msg: &Messages) {
+            self.push(format!("enter {}", state.name));
+        }
+
+        fn on_process(&mut self, state: StateRef, msg: &Messages, handled: bool) {
+            self.push(format!("process {} {msg:?} {handled}", state.name));
+        }
+
+        fn on_exit(&mut self, state: StateRef, _msg: &Messages) {
+            self.push(format!("exit {}", state.name));
+        }
+
+        fn on_transition(
+            &mut self,
+            src: StateRef,
+            dest: StateRef,
+            ancestor: Option<StateRef>,
+            _msg: &Messages,
+        ) {
+            self.push(format!(
+                "transition {} {} {:?}",
+                src.name,
+                dest.name,
+                ancestor.map(|state| state.name)
+            ));
+        }
+
+        fn on_defer(&mut self, state: StateRef, msg: &Messages) {
+            self.push(format!("defer {} {msg:?}", state.name));
+        }
+
+        fn on_defer_dropped(&mut self, msg: &Messages) {
+            self.push(format!("dropped {msg:?}"));
+        }
+
+        fn on_deferred_replay(&mut self, msg: &Messages) {
+            self.push(format!("replay {msg:?}"));
+        }
+    }
+
+    impl Recorder {
+        fn push(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    enum Messages {
+        Go,
+        Later,
+        Hold,
+    }
+
+    #[derive(Debug)]
+    struct StateMachine;
+
+    //        base
+    //       /    \
+    //  initial    other
+    const MAX_STATES: usize = 3;
+    const IDX_BASE: usize = 0;
+    const IDX_INITIAL: usize = 1;
+    const IDX_OTHER: usize = 2;
+
+    impl StateMachine {
+        #[no_coverage]
+        fn base(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+            match msg {
+                Messages::Go => (Handled::Yes, Some(IDX_OTHER)),
+                Messages::Later | Messages::Hold => (Handled::Yes, None),
+            }
+        }
+
+        #[no_coverage]
+        fn initial(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+            match msg {
+                Messages::Later => {
+                    e.defer_current(msg.clone()).unwrap();
+                    (Handled::Yes, None)
+                }
+                _ => (Handled::No, None),
+            }
+        }
+
+        #[no_coverage]
+        fn other(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+            (Handled::No, None)
+        }
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_observer() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut sme = Executor::new(RefCell::new(StateMachine), MAX_STATES)
+            .state(
+                StateInfo::new("base", StateMachine::base)
+                    .defer(|msg| matches!(msg, Messages::Hold)),
+            )
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_BASE))
+            .state(StateInfo::new("other", StateMachine::other).parent_idx(IDX_BASE))
+            .defer_capacity(1)
+            .defer_overflow_policy(DeferOverflowPolicy::DropNewest)
+            .observer(TraceObserver)
+            .observer(Recorder {
+                events: events.clone(),
+            })
+            .build(IDX_INITIAL)
+            .unwrap();
+
+        // The second Later is dropped, it isn't reported as deferred
+        sme.dispatcher(&Messages::Later).unwrap();
+        sme.dispatcher(&Messages::Later).unwrap();
+        sme.dispatcher(&Messages::Go).unwrap();
+        assert_eq!(sme.get_current_state_name(), "other");
+
+        // Hold is deferred by base, not the current state
+        sme.dispatcher(&Messages::Hold).unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "enter base",
+                "enter initial",
+                "defer initial Later",
+                "process initial Later true",
+                "dropped Later",
+                "process initial Later true",
+                "process initial Go false",
+                "process base Go true",
+                "transition base other Some(\"base\")",
+                "exit initial",
+                "replay Later",
+                "enter other",
+                "process other Later false",
+                "process base Later true",
+                "defer base Hold",
+            ]
+        );
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    fmt::{self, Debug, Display},
+    io::{BufRead, Write},
+    time::Instant,
+};
+
+use crate::{DispatchError, Executor, MsgInfo, StateId};
+
+// How a message is written to and read from a recording,
+// needed by Executor::record and Executor::replay.
+//
+// encode may return any text, it's escaped when written.
+pub trait Recordable: Sized {
+    fn encode(&self) -> String;
+    fn decode(s: &str) -> Result<Self, String>;
+}
+
+// Captures the messages received by Executor::dispatcher
+pub(crate) struct Recorder<P> {
+    // None while replaying, only the transitions are needed
+    writer: Option<Box<dyn Write + Send>>,
+    encode: fn(&P) -> String,
+
+    // When the first message recorded was accepted
+    start: Option<Instant>,
+
+    // The destinations of the transitions made by the message
+    // being dispatched, including those of deferred messages
+    transitions: Vec<usize>,
+}
+
+impl<P> Recorder<P> {
+    fn new(writer: Option<Box<dyn Write + Send>>) -> Self
+    where
+        P: Recordable,
+    {
+        Self {
+            writer,
+            encode: P::encode,
+            start: None,
+            transitions: Vec::new(),
+        }
+    }
+
+    pub(crate) fn begin(&mut self, msg: &P) -> String {
+        self.transitions.clear();
+        (self.encode)(msg)
+    }
+
+    pub(crate) fn transition(&mut self, idx_dest: usize) {
+        self.transitions.push(idx_dest);
+    }
+}
+
+// A line of a recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordEntry {
+    // The MsgInfo::seq of the message
+    pub seq: u64,
+
+    // When the message was accepted, its MsgInfo::enqueued_at,
+    // relative to the first message recorded
+    pub nanos: u64,
+
+    // The names of the states transitioned to, in order
+    pub transitions: Vec<String>,
+
+    // The message as encoded by Recordable::encode
+    pub msg: String,
+}
+
+// Each entry is one line of tab separated fields, see
+// RecordEntry::parse. Tabs, newlines, commas and backslashes
+// in names and messages are escaped with a backslash.
+impl Display for RecordEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let transitions: Vec<String> = self.transitions.iter().map(|t| escape(t)).collect();
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.seq,
+            self.nanos,
+            transitions.join(","),
+            escape(&self.msg)
+        )
+    }
+}
+
+impl RecordEntry {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 4 {
+            return Err(format!("expected 4 fields, found {}", fields.len()));
+        }
+
+        let seq = fields[0]
+            .parse()
+            .map_err(|_| format!("invalid seq '{}'", fields[0]))?;
+        let nanos = fields[1]
+            .parse()
+            .map_err(|_| format!("invalid nanos '{}'", fields[1]))?;
+        let transitions = if fields[2].is_empty() {
+            Vec::new()
+        } else {
+            split_escaped(fields[2], ',')
+                .iter()
+                .map(|t| unescape(t))
+                .collect::<Result<_, _>>()?
+        };
+        let msg = unescape(fields[3])?;
+
+        Ok(Self {
+            seq,
+            nanos,
+            transitions,
+            msg,
+        })
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            ',' => escaped.push_str("\\,"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(',') => unescaped.push(','),
+            Some(c) => return Err(format!("invalid escape '\\{c}' in '{s}'")),
+            None => return Err(format!("trailing '\\' in '{s}'")),
+        }
+    }
+
+    Ok(unescaped)
+}
+
+// Split on the separators that aren't escaped
+fn split_escaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (idx, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&s[start..idx]);
+            start = idx + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+
+    parts
+}
+
+// The first message whose transitions weren't the recorded ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub seq: u64,
+    pub msg: String,
+    pub expected: Vec<String>,
+    pub actual: Vec<String>,
+}
+
+// Errors returned by Executor::replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    // Reading the recording failed
+    Io(String),
+
+    // A line, numbered from 1, isn't a valid entry or its msg
+    // couldn't be decoded
+    Parse { line: usize, reason: String },
+
+    // Dispatching the message with seq failed
+    Dispatch { seq: u64, error: DispatchError },
+
+    Diverged(Divergence),
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(error) => write!(f, "Reading the recording failed: {error}"),
+            ReplayError::Parse { line, reason } => write!(f, "Line {line} is invalid: {reason}"),
+            ReplayError::Dispatch { seq, error } => {
+                write!(f, "Dispatching seq {seq} failed: {error}")
+            }
+            ReplayError::Diverged(d) => write!(
+                f,
+                "Diverged at seq {} msg={}, expected transitions {:?} but got {:?}",
+                d.seq, d.msg, d.expected, d.actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl<SM, P, S> Executor<SM, P, S>
+where
+    SM: Debug,
+    P: Debug,
+    S: StateId,
+{
+    // Record each message received by dispatcher, dispatcher_mut
+    // and dispatcher_owned, and the transitions it caused, to writer
+    // as a RecordEntry per line. Replay it with Executor::replay.
+    pub fn record(mut self, writer: impl Write + Send + 'static) -> Self
+    where
+        P: Recordable,
+    {
+        self.recorder = Some(Recorder::new(Some(Box::new(writer))));
+
+        self
+    }
+
+    // Called by the dispatchers after msg, whose encoding is
+    // encoded, has been dispatched with info.
+    pub(crate) fn record_dispatched(
+        &mut self,
+        encoded: String,
+        info: MsgInfo,
+    ) -> Result<(), DispatchError> {
+        let recorder = self.recorder.as_ref().expect("SNH, not recording");
+        let start = recorder.start.unwrap_or(info.enqueued_at);
+        let entry = RecordEntry {
+            seq: info.seq,
+            nanos: info.enqueued_at.saturating_duration_since(start).as_nanos() as u64,
+            transitions: self.state_names(&recorder.transitions),
+            msg: encoded,
+        };
+
+        let recorder = self.recorder.as_mut().expect("SNH, not recording");
+        recorder.start = Some(start);
+        if let Some(writer) = recorder.writer.as_mut() {
+            writeln!(writer, "{entry}")
+                .and_then(|_| writer.flush())
+                .map_err(|error| DispatchError::Record {
+                    seq: entry.seq,
+                    error: error.to_string(),
+                })?;
+        }
+
+        Ok(())
+    }
+
+    // Dispatch the messages of a recording with dispatcher_owned and
+    // check they cause the recorded transitions. Returns the number
+    // of messages replayed or the first divergence. The timestamps
+    // aren't replayed, use a Simulation if the timing matters.
+    // Draining is disabled as the drained messages were recorded.
+    pub fn replay(&mut self, reader: impl BufRead) -> Result<u64, ReplayError>
+    where
+        P: Recordable,
+    {
+        let installed = self.recorder.is_none();
+        if installed {
+            self.recorder = Some(Recorder::new(None));
+        }
+        let drain_budget = self.drain_budget.take();
+        let result = self.replay_entries(reader);
+        self.drain_budget = drain_budget;
+        if installed {
+            self.recorder = None;
+        }
+
+        result
+    }
+
+    fn replay_entries(&mut self, reader: impl BufRead) -> Result<u64, ReplayError>
+    where
+        P: Recordable,
+    {
+        let mut replayed = 0;
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line.map_err(|error| ReplayError::Io(error.to_string()))?;
+            let parse_error = |reason| ReplayError::Parse {
+                line: idx + 1,
+                reason,
+            };
+            let entry = RecordEntry::parse(&line).map_err(parse_error)?;
+            let msg = P::decode(&entry.msg).map_err(parse_error)?;
+
+            self.dispatcher_owned(msg)
+                .map_err(|error| ReplayError::Dispatch {
+                    seq: entry.seq,
+                    error,
+                })?;
+            let recorder = self.recorder.as_ref().expect("SNH, not recording");
+            let actual = self.state_names(&recorder.transitions);
+            if actual != entry.transitions {
+                return Err(ReplayError::Diverged(Divergence {
+                    seq: entry.seq,
+                    msg: entry.msg,
+                    expected: entry.transitions,
+                    actual,
+                }));
+            }
+            replayed += 1;
+        }
+
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        io::Cursor,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{Handled, ManualClock, StateInfo, StateResult};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Messages {
+        Work { name: String },
+        Done,
+    }
+
+    impl Recordable for Messages {
+        fn encode(&self) -> String {
+            match self {
+                Messages::Work { name } => format!("Work {name}"),
+                Messages::Done => "Done".to_owned(),
+            }
+        }
+
+        fn decode(s: &str) -> Result<Self, String> {
+            match s.split_once(' ') {
+                Some(("Work", name)) => Ok(Messages::Work {
+                    name: name.to_owned(),
+                }),
+                None if s == "Done" => Ok(Messages::Done),
+                _ => Err(format!("unknown msg '{s}'")),
+            }
+        }
+    }
+
+    // A writer whose output can be read after it's been
+    // moved into the Executor
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct StateMachine {
+        // When true busy ignores Done
+        broken: bool,
+    }
+
+    const MAX_STATES: usize = 2;
+    const IDX_IDLE: usize = 0;
+    const IDX_BUSY: usize = 1;
+
+    impl StateMachine {
+        #[no_coverage]
+        fn new(broken: bool) -> Executor<Self, Messages> {
+            let sm = RefCell::new(StateMachine { broken });
+            Executor::new(sm, MAX_STATES)
+                .state(StateInfo::new("idle", Self::idle))
+                .state(StateInfo::new("busy", Self::busy))
+                .build(IDX_IDLE)
+                .expect("Unexpected error initializing")
+        }
+
+        #[no_coverage]
+        fn idle(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+            match msg {
+                Messages::Work { .. } => (Handled::Yes, Some(IDX_BUSY)),
+                Messages::Done => (Handled::Yes, None),
+            }
+        }
+
+        #[no_coverage]
+        fn busy(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+            match msg {
+                Messages::Work { .. } => {
+                    e.defer_current(msg.clone()).unwrap();
+                    (Handled::Yes, None)
+                }
+                Messages::Done if !self.broken => (Handled::Yes, Some(IDX_IDLE)),
+                Messages::Done => (Handled::Yes, None),
+            }
+        }
+    }
+
+    #[no_coverage]
+    fn work(name: &str) -> Messages {
+        Messages::Work {
+            name: name.to_owned(),
+        }
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_record_replay() {
+        let buf = SharedBuf::default();
+        let clock = ManualClock::new();
+        let mut sme = StateMachine::new(false)
+            .clock(Arc::new(clock.clone()))
+            .record(buf.clone());
+        sme.dispatcher(&work("a,\tb")).unwrap();
+        clock.advance(Duration::from_nanos(5));
+        sme.dispatcher(&work("c")).unwrap();
+        sme.dispatcher_owned(Messages::Done).unwrap();
+        sme.dispatcher_mut(&mut Messages::Done).unwrap();
+
+        let recording = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let entries: Vec<RecordEntry> = recording
+            .lines()
+            .map(|line| RecordEntry::parse(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 4);
+
+        // The entries have the seqs and times the messages were
+        // stamped with
+        assert_eq!(
+            entries.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!(
+            entries.iter().map(|e| e.nanos).collect::<Vec<_>>(),
+            vec![0, 5, 5, 5]
+        );
+        assert_eq!(entries[0].msg, "Work a,\tb");
+        assert_eq!(entries[0].transitions, vec!["busy"]);
+        assert!(entries[1].transitions.is_empty());
+
+        // Done returns to idle, the deferred Work goes back to busy
+        assert_eq!(entries[2].transitions, vec!["idle", "busy"]);
+        assert_eq!(entries[3].transitions, vec!["idle"]);
+        for entry in entries.iter() {
+            assert_eq!(RecordEntry::parse(&entry.to_string()).unwrap(), *entry);
+        }
+
+        let mut fresh = StateMachine::new(false);
+        assert_eq!(fresh.replay(Cursor::new(&recording)), Ok(4));
+        assert_eq!(fresh.get_current_state_name(), "idle");
+
+        let mut broken = StateMachine::new(true);
+        assert_eq!(
+            broken.replay(Cursor::new(&recording)),
+            Err(ReplayError::Diverged(Divergence {
+                seq: 2,
+                msg: "Done".to_owned(),
+                expected: vec!["idle".to_owned(), "busy".to_owned()],
+                actual: vec![],
+            }))
+        );
+
+        let mut fresh = StateMachine::new(false);
+        assert_eq!(
+            fresh.replay(Cursor::new("0\t0\tbusy\tWork a\n1\t0\t\tRest\n")),
+            Err(ReplayError::Parse {
+                line: 2,
+                reason: "unknown msg 'Rest'".to_owned()
+            })
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{SendError, Sender},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{Clock, DispatchError, Executor, MsgInfo, StateId, Status, SystemClock, TimerId};
+
+// What is sent on the primary channel of an Executor
+pub(crate) enum Envelope<P> {
+    Msg(P, MsgInfo),
+
+    // The msg of an expired timer, dropped when received
+    // if the timer was cancelled after it expired
+    Timer(TimerId, P, MsgInfo),
+
+    // Ends Executor::run, see MsgSender::stop
+    Stop,
+}
+
+// Sends messages to an Executor, returned by Executor::clone_sender
+pub struct MsgSender<P> {
+    tx: Sender<Envelope<P>>,
+
+    // Shared by the executor and all its senders
+    seq: Arc<AtomicU64>,
+    clock: Arc<RwLock<Arc<dyn Clock>>>,
+}
+
+impl<P> Clone for MsgSender<P> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            seq: self.seq.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<P> Debug for MsgSender<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MsgSender").finish_non_exhaustive()
+    }
+}
+
+impl<P> MsgSender<P> {
+    pub(crate) fn new(tx: Sender<Envelope<P>>) -> Self {
+        Self {
+            tx,
+            seq: Arc::new(AtomicU64::new(0)),
+            clock: Arc::new(RwLock::new(Arc::new(SystemClock))),
+        }
+    }
+
+    // Changes the clock of all the clones too
+    pub(crate) fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.write().unwrap() = clock;
+    }
+
+    // Take the next seq
+    pub(crate) fn stamp(&self) -> MsgInfo {
+        MsgInfo {
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            enqueued_at: self.clock.read().unwrap().now(),
+        }
+    }
+
+    // The seq the next message will be stamped with
+    pub(crate) fn next_seq(&self) -> u64 {
+        self.seq.load(Ordering::Relaxed)
+    }
+
+    // Make sure the next seq is at least next_seq
+    pub(crate) fn advance_seq(&self, next_seq: u64) {
+        self.seq.fetch_max(next_seq, Ordering::Relaxed);
+    }
+
+    pub fn send(&self, msg: P) -> Result<(), SendError<P>> {
+        self.tx
+            .send(Envelope::Msg(msg, self.stamp()))
+            .map_err(|SendError(envelope)| match envelope {
+                Envelope::Msg(msg, _) => SendError(msg),
+                _ => panic!("SNH, sent Envelope::Msg"),
+            })
+    }
+
+    // Send the msg of the expired timer id
+    pub(crate) fn send_timer(&self, id: TimerId, msg: P) -> bool {
+        self.tx.send(Envelope::Timer(id, msg, self.stamp())).is_ok()
+    }
+
+    // Ask Executor::run to return once the messages sent
+    // before this have been dispatched. Returns false if
+    // the executor no longer exists.
+    pub fn stop(&self) -> bool {
+        self.tx.send(Envelope::Stop).is_ok()
+    }
+}
+
+// Returned by Executor::spawn
+pub struct ExecutorHandle<SM, P> {
+    sender: MsgSender<P>,
+    thread: JoinHandle<Result<SM, DispatchError>>,
+}
+
+impl<SM, P> ExecutorHandle<SM, P> {
+    pub fn send(&self, msg: P) -> Result<(), SendError<P>> {
+        self.sender.send(msg)
+    }
+
+    pub fn sender(&self) -> MsgSender<P> {
+        self.sender.clone()
+    }
+
+    // Ask the executor to stop, see MsgSender::stop
+    pub fn stop(&self) -> bool {
+        self.sender.stop()
+    }
+
+    // Wait for the executor to stop and return its state machine.
+    // A panic in the executor's thread is resumed here.
+    pub fn join(self) -> Result<SM, DispatchError> {
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl<SM, P, S> Executor<SM, P, S>
+where
+    SM: Debug,
+    P: Debug,
+    S: StateId,
+{
+    // Receive and dispatch messages until stopped with
+    // MsgSender::stop or Executor::request_stop, or the
+    // machine terminates, see Status::Terminated.
+    pub fn run(&mut self) -> Result<(), DispatchError> {
+        while let Ok((mut msg, info)) = self.recv_with_info() {
+            if self.dispatcher_with_info(&mut msg, info)? == Status::Terminated {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Same as run but also returns after dispatching a message
+    // when done returns true. Returns true if done did.
+    pub fn run_until<F>(&mut self, mut done: F) -> Result<bool, DispatchError>
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        if done(self) {
+            return Ok(true);
+        }
+        while let Ok((mut msg, info)) = self.recv_with_info() {
+            let status = self.dispatcher_with_info(&mut msg, info)?;
+            if done(self) {
+                return Ok(true);
+            }
+            if status == Status::Terminated {
+                break;
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Move the executor to its own thread which starts it, runs
+    // it until stopped then stops it. start and stop pass
+    // P::default() to the enter and exit fns.
+    pub fn spawn(mut self) -> ExecutorHandle<SM, P>
+    where
+        SM: Send + 'static,
+        P: Default + Send + 'static,
+        S: Send + 'static,
+    {
+        let sender = self.clone_sender();
+        let thread = thread::spawn(move || {
+            self.start();
+            let result = self.run();
+            self.stop();
+            result.map(|_| self.sm.into_inner())
+        });
+
+        ExecutorHandle { sender, thread }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, time::Duration};
+
+    use super::*;
+    use crate::{Handled, ManualClock, StateInfo, StateResult};
+
+    #[derive(Debug, Default)]
+    enum Messages {
+        #[default]
+        Lifecycle,
+        Add {
+            val: i32,
+        },
+        StopWhenZero,
+    }
+
+    #[derive(Debug, Default)]
+    struct StateMachine {
+        sum: i32,
+        entered: bool,
+        exited: bool,
+    }
+
+    const MAX_STATES: usize = 1;
+    const IDX_STATE1: usize = 0;
+
+    impl StateMachine {
+        #[no_coverage]
+        fn new() -> Executor<Self, Messages> {
+            let sm = RefCell::new(StateMachine::default());
+            Executor::new(sm, MAX_STATES)
+                .state(
+                    StateInfo::new("state1", Self::state1)
+                        .enter_fn(Self::state1_enter)
+                        .exit_fn(Self::state1_exit),
+                )
+                .build(IDX_STATE1)
+                .expect("Unexpected error initializing")
+        }
+
+        #[no_coverage]
+        fn state1_enter(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
+            self.entered = true;
+        }
+
+        #[no_coverage]
+        fn state1_exit(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) {
+            self.exited = true;
+        }
+
+        #[no_coverage]
+        fn state1(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+            match msg {
+                Messages::Add { val } => self.sum += val,
+                Messages::StopWhenZero => {
+                    if self.sum == 0 {
+                        e.request_stop();
+                    }
+                }
+                Messages::Lifecycle => (),
+            }
+            (Handled::Yes, None)
+        }
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_spawn() {
+        let handle = StateMachine::new().spawn();
+        let sender = handle.sender();
+        for val in 1..=10 {
+            sender.send(Messages::Add { val }).unwrap();
+        }
+        handle.send(Messages::Add { val: 1 }).unwrap();
+        assert!(handle.stop());
+
+        let sm = handle.join().unwrap();
+        assert_eq!(sm.sum, 56);
+        assert!(sm.entered);
+        assert!(sm.exited);
+
+        // The executor is gone
+        assert!(!sender.stop());
+        assert!(sender.send(Messages::Add { val: 1 }).is_err());
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_run_until() {
+        let mut sme = StateMachine::new();
+        for val in [1, 2, -3, 4] {
+            sme.send(Messages::Add { val }).unwrap();
+        }
+        sme.send(Messages::StopWhenZero).unwrap();
+
+        assert!(sme.run_until(|e| e.get_sm().borrow().sum == 3).unwrap());
+        assert!(sme.run_until(|e| e.get_sm().borrow().sum == 3).unwrap());
+        assert!(sme.run_until(|e| e.get_sm().borrow().sum == 0).unwrap());
+
+        // request_stop stops run after the messages already sent
+        sme.send(Messages::Add { val: -4 }).unwrap();
+        sme.send(Messages::StopWhenZero).unwrap();
+        sme.send(Messages::Add { val: 7 }).unwrap();
+        sme.run().unwrap();
+        assert_eq!(sme.get_sm().borrow().sum, 7);
+        assert!(sme.try_recv().is_err());
+
+        // run_until returns false when stopped
+        sme.clone_sender().stop();
+        assert!(!sme.run_until(|_| false).unwrap());
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_sender_clock() {
+        // A sender cloned before the clock is set stamps with it too
+        let clock = ManualClock::new();
+        clock.advance(Duration::from_secs(3600));
+        let sme = StateMachine::new();
+        let sender = sme.clone_sender();
+        let sme = sme.clock(Arc::new(clock.clone()));
+        sender.send(Messages::Add { val: 1 }).unwrap();
+        let (_, info) = sme.try_recv_with_info().unwrap();
+        assert_eq!(info.enqueued_at, clock.now());
+    }
+}
@@ -0,0 +1,486 @@
+use std::{
+    any::Any,
+    fmt::{self, Debug, Display},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{Clock, DispatchError, Executor, ManualClock, MsgInfo, StateId};
+
+// Set to reproduce a simulation, Simulation::new uses it as the seed
+pub const SEED_ENV_VAR: &str = "HSM0_SIM_SEED";
+
+// Returned by Simulation::step and friends when a dispatch
+// fails, with the seed to reproduce the run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimError {
+    pub seed: u64,
+    pub steps: usize,
+    pub error: DispatchError,
+}
+
+impl Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Simulation failed with seed {seed} after {steps} steps, reproduce with {SEED_ENV_VAR}={seed}: {error}",
+            seed = self.seed,
+            steps = self.steps,
+            error = self.error
+        )
+    }
+}
+
+impl std::error::Error for SimError {}
+
+// Identifies an executor added to a Simulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimId(usize);
+
+// An executor and the message received from its queue
+// but not yet dispatched.
+struct SimNode<SM, P, S>
+where
+    SM: Debug,
+    P: Debug,
+    S: StateId,
+{
+    executor: Executor<SM, P, S>,
+    pending: Option<(P, MsgInfo)>,
+}
+
+// Lets the simulation hold executors of different types
+trait SimExecutor {
+    fn ready(&mut self) -> bool;
+    fn step(&mut self) -> Result<(), DispatchError>;
+    fn next_timer_deadline(&self) -> Option<Instant>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<SM, P, S> SimExecutor for SimNode<SM, P, S>
+where
+    SM: Debug + 'static,
+    P: Debug + 'static,
+    S: StateId + 'static,
+{
+    fn ready(&mut self) -> bool {
+        if self.pending.is_none() {
+            self.pending = self.executor.try_recv_with_info().ok();
+        }
+
+        self.pending.is_some()
+    }
+
+    fn step(&mut self) -> Result<(), DispatchError> {
+        match self.pending.take() {
+            Some((mut msg, info)) => self
+                .executor
+                .dispatcher_with_info(&mut msg, info)
+                .map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn next_timer_deadline(&self) -> Option<Instant> {
+        self.executor.next_timer_deadline()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Runs executors deterministically in a single thread.
+//
+// Time only changes with advance and, of the executors with a
+// message ready, the one dispatched next is chosen randomly
+// using the seed. So a run with the same seed, executors and
+// messages is always the same. A failed dispatch returns a
+// SimError with the seed and if a panic occurs while the
+// simulation is alive the seed is printed, set SEED_ENV_VAR
+// to it to reproduce the run.
+//
+// add installs the virtual clock in the executors, build them
+// with `.clock(sim.clock())` so timers started before they're
+// added are deterministic too. It also disables draining, see
+// Executor::drain_budget, so each message is a step chosen
+// using the seed.
+pub struct Simulation {
+    seed: u64,
+    rng: StdRng,
+    clock: ManualClock,
+    nodes: Vec<Box<dyn SimExecutor>>,
+    steps: usize,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulation {
+    // A simulation seeded from SEED_ENV_VAR, or randomly if it isn't set
+    pub fn new() -> Self {
+        let seed = match std::env::var(SEED_ENV_VAR) {
+            Ok(seed) => seed
+                .parse()
+                .unwrap_or_else(|_| panic!("{SEED_ENV_VAR}={seed} is not a u64")),
+            Err(_) => rand::random(),
+        };
+
+        Self::with_seed(seed)
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            clock: ManualClock::new(),
+            nodes: Vec::new(),
+            steps: 0,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // The number of messages dispatched so far
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    // The virtual clock, pass it to Executor::clock
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(self.clock.clone())
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    // Add an executor, its clock is replaced by the virtual clock
+    // and its pending timers keep the time remaining until they
+    // expire. Its drain_budget is cleared, the simulation
+    // interleaves the messages it sends itself with the others.
+    pub fn add<SM, P, S>(&mut self, executor: Executor<SM, P, S>) -> SimId
+    where
+        SM: Debug + 'static,
+        P: Debug + 'static,
+        S: StateId + 'static,
+    {
+        let mut executor = executor.clock(self.clock());
+        executor.drain_budget = None;
+        self.nodes.push(Box::new(SimNode {
+            executor,
+            pending: None,
+        }));
+
+        SimId(self.nodes.len() - 1)
+    }
+
+    pub fn executor<SM, P, S>(&self, id: SimId) -> &Executor<SM, P, S>
+    where
+        SM: Debug + 'static,
+        P: Debug + 'static,
+        S: StateId + 'static,
+    {
+        match self.nodes[id.0]
+            .as_any()
+            .downcast_ref::<SimNode<SM, P, S>>()
+        {
+            Some(node) => &node.executor,
+            None => panic!("{id:?} is not an Executor of the requested type"),
+        }
+    }
+
+    pub fn executor_mut<SM, P, S>(&mut self, id: SimId) -> &mut Executor<SM, P, S>
+    where
+        SM: Debug + 'static,
+        P: Debug + 'static,
+        S: StateId + 'static,
+    {
+        match self.nodes[id.0]
+            .as_any_mut()
+            .downcast_mut::<SimNode<SM, P, S>>()
+        {
+            Some(node) => &mut node.executor,
+            None => panic!("{id:?} is not an Executor of the requested type"),
+        }
+    }
+
+    // Dispatch one message from a randomly chosen executor that
+    // has one ready, returns false if none are ready.
+    pub fn step(&mut self) -> Result<bool, SimError> {
+        let ready: Vec<usize> = (0..self.nodes.len())
+            .filter(|idx| self.nodes[*idx].ready())
+            .collect();
+        if ready.is_empty() {
+            return Ok(false);
+        }
+
+        let idx = ready[self.rng.gen_range(0..ready.len())];
+        self.steps += 1;
+        if let Err(error) = self.nodes[idx].step() {
+            return Err(SimError {
+                seed: self.seed,
+                steps: self.steps,
+                error,
+            });
+        }
+
+        Ok(true)
+    }
+
+    // Dispatch messages until no executor has one ready,
+    // returns the number dispatched.
+    pub fn run_until_idle(&mut self) -> Result<usize, SimError> {
+        let steps = self.steps;
+        while self.step()? {}
+
+        Ok(self.steps - steps)
+    }
+
+    // Advance the clock by duration. The clock stops at each timer
+    // deadline on the way and runs until idle so timers expire in
+    // order and see the effects of earlier timers.
+    pub fn advance(&mut self, duration: Duration) -> Result<usize, SimError> {
+        let end = self.clock.now() + duration;
+        let steps = self.steps;
+        self.run_until_idle()?;
+        while let Some(deadline) = self.next_timer_deadline() {
+            if deadline > end {
+                break;
+            }
+            self.clock
+                .advance(deadline.saturating_duration_since(self.clock.now()));
+            self.run_until_idle()?;
+        }
+        self.clock
+            .advance(end.saturating_duration_since(self.clock.now()));
+        self.run_until_idle()?;
+
+        Ok(self.steps - steps)
+    }
+
+    fn next_timer_deadline(&self) -> Option<Instant> {
+        self.nodes
+            .iter()
+            .filter_map(|node| node.next_timer_deadline())
+            .min()
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            eprintln!(
+                "Simulation failed with seed {seed} after {steps} steps, reproduce with {SEED_ENV_VAR}={seed}",
+                seed = self.seed,
+                steps = self.steps
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, sync::Mutex};
+
+    use super::*;
+    use crate::{Handled, InvalidTransitionPolicy, StateInfo, StateResult, SystemClock};
+
+    #[derive(Debug, Clone)]
+    enum Messages {
+        Val { val: usize },
+        Timeout,
+        Tick,
+        Bad,
+    }
+
+    #[derive(Debug)]
+    struct StateMachine {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    const MAX_STATES: usize = 2;
+    const IDX_WAIT: usize = 0;
+    const IDX_TIMED_OUT: usize = 1;
+
+    impl StateMachine {
+        #[no_coverage]
+        fn new(
+            sim: &Simulation,
+            name: &'static str,
+            log: &Arc<Mutex<Vec<String>>>,
+        ) -> Executor<Self, Messages> {
+            let sm = RefCell::new(StateMachine {
+                name,
+                log: log.clone(),
+            });
+            Executor::new(sm, MAX_STATES)
+                .state(StateInfo::new("wait", Self::wait).enter_fn(Self::wait_enter))
+                .state(StateInfo::new("timed_out", Self::timed_out))
+                .clock(sim.clock())
+                .invalid_transition_policy(InvalidTransitionPolicy::ReturnError)
+                .build(IDX_WAIT)
+                .expect("Unexpected error initializing")
+        }
+
+        #[no_coverage]
+        fn wait_enter(&mut self, e: &Executor<Self, Messages>, _msg: &Messages) {
+            e.state_send_after(Duration::from_secs(2), Messages::Timeout);
+        }
+
+        #[no_coverage]
+        fn wait(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+            match msg {
+                Messages::Timeout => (Handled::Yes, Some(IDX_TIMED_OUT)),
+                Messages::Bad => (Handled::Yes, Some(MAX_STATES)),
+                _ => (Handled::No, None),
+            }
+        }
+
+        #[no_coverage]
+        fn timed_out(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+            let entry = match msg {
+                Messages::Val { val } => format!("{}:Val{val}", self.name),
+                _ => format!("{}:{msg:?}", self.name),
+            };
+            self.log.lock().unwrap().push(entry);
+            (Handled::Yes, None)
+        }
+    }
+
+    #[no_coverage]
+    fn run(seed: u64) -> Vec<String> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut sim = Simulation::with_seed(seed);
+        for name in ["a", "b", "c"] {
+            let mut sme = StateMachine::new(&sim, name, &log);
+            sme.start_with(&Messages::Tick);
+            for val in 0..5 {
+                sme.send(Messages::Val { val }).unwrap();
+            }
+            sim.add(sme);
+        }
+
+        // Nothing is logged while waiting, the timeout takes 2s
+        assert_eq!(sim.run_until_idle().unwrap(), 15);
+        assert_eq!(sim.advance(Duration::from_millis(1999)).unwrap(), 0);
+        assert_eq!(sim.advance(Duration::from_millis(1)).unwrap(), 3);
+        for idx in 0..3 {
+            let sme = sim.executor::<StateMachine, Messages, usize>(SimId(idx));
+            assert_eq!(sme.get_current_state_name(), "timed_out");
+        }
+
+        // Randomly interleave the executors
+        for (idx, name) in ["a", "b", "c"].iter().enumerate() {
+            let sme = sim.executor_mut::<StateMachine, Messages, usize>(SimId(idx));
+            sme.send_every(Duration::from_secs(1), Messages::Tick)
+                .unwrap();
+            for val in 0..3 {
+                sme.send(Messages::Val { val }).unwrap();
+            }
+            assert_eq!(sme.get_sm().borrow().name, *name);
+        }
+        assert_eq!(sim.run_until_idle().unwrap(), 9);
+        assert_eq!(sim.advance(Duration::from_secs(3)).unwrap(), 9);
+        assert_eq!(sim.steps(), 15 + 3 + 9 + 9);
+
+        let log = log.lock().unwrap();
+        log.clone()
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_simulation_is_deterministic() {
+        let log = run(1);
+        assert_eq!(log.len(), 9 + 9);
+        assert_eq!(log, run(1), "seed 1 isn't deterministic");
+        assert_ne!(log, run(2), "seeds 1 and 2 have the same order");
+
+        // The ticks of each second are all processed
+        // before the ticks of the next second
+        let ticks: Vec<&String> = log.iter().filter(|l| l.ends_with("Tick")).collect();
+        for second in ticks.chunks(3) {
+            let mut names: Vec<&str> = second.iter().map(|l| &l[..1]).collect();
+            names.sort();
+            assert_eq!(names, vec!["a", "b", "c"]);
+        }
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_simulation_installs_clock() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut sim = Simulation::with_seed(0);
+        sim.advance(Duration::from_secs(10)).unwrap();
+
+        // Built with the system clock, the 2s timer keeps its remaining time
+        let mut sme = StateMachine::new(&sim, "a", &log).clock(Arc::new(SystemClock));
+        sme.start_with(&Messages::Tick);
+        let id = sim.add(sme);
+        let sme = sim.executor::<StateMachine, Messages, usize>(id);
+        assert_eq!(sme.now(), sim.now());
+        let deadline = sme.next_timer_deadline().unwrap();
+        assert!(deadline > sim.now() + Duration::from_secs(1));
+        assert!(deadline <= sim.now() + Duration::from_secs(2));
+
+        assert_eq!(sim.advance(Duration::from_secs(2)).unwrap(), 1);
+        let sme = sim.executor::<StateMachine, Messages, usize>(id);
+        assert_eq!(sme.get_current_state_name(), "timed_out");
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_simulation_disables_draining() {
+        // Draining would dispatch all the Vals in the first step
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut sim = Simulation::with_seed(0);
+        let mut sme = StateMachine::new(&sim, "a", &log).drain_budget(10);
+        sme.start_with(&Messages::Tick);
+        for val in 0..5 {
+            sme.send(Messages::Val { val }).unwrap();
+        }
+        sim.add(sme);
+
+        assert_eq!(sim.run_until_idle().unwrap(), 5);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_simulation_error_has_seed() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut sim = Simulation::with_seed(7);
+        let mut sme = StateMachine::new(&sim, "a", &log);
+        sme.start_with(&Messages::Tick);
+        sme.send(Messages::Bad).unwrap();
+        sim.add(sme);
+
+        let error = sim.run_until_idle().unwrap_err();
+        assert_eq!(error.seed, 7);
+        assert_eq!(error.steps, 1);
+        assert!(matches!(error.error, DispatchError::InvalidTransition(_)));
+        assert!(error.to_string().contains("HSM0_SIM_SEED=7"));
+    }
+
+    #[test]
+    #[no_coverage]
+    #[should_panic(expected = "is not an Executor of the requested type")]
+    fn test_simulation_wrong_executor_type() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut sim = Simulation::with_seed(0);
+        let id = sim.add(StateMachine::new(&sim, "a", &log));
+        sim.executor::<StateMachine, u32, usize>(id);
+    }
+}
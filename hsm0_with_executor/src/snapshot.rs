@@ -0,0 +1,513 @@
+use std::{
+    fmt::{self, Debug, Display},
+    time::Duration,
+};
+
+use crate::{Deferred, Executor, History, MsgInfo, StateId, StateInfo};
+
+// The state of a StateInfo saved in a Snapshot, name, parent,
+// parallel, history, is_final and initial_child identify the
+// state table the snapshot came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateSnapshot {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub parallel: bool,
+    pub history: Option<History>,
+    pub is_final: bool,
+    pub initial_child: Option<usize>,
+    pub active: bool,
+    pub enter_cnt: usize,
+    pub process_cnt: usize,
+    pub exit_cnt: usize,
+    pub last_active_child: Option<usize>,
+}
+
+// A deferred message saved in a Snapshot with its MsgInfo::seq,
+// how long ago it was accepted and how many times it's been replayed
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeferredSnapshot<P> {
+    pub msg: P,
+    pub seq: u64,
+    pub age: Duration,
+    pub replays: usize,
+}
+
+// What Executor::snapshot saves and Executor::restore restores.
+//
+// The messages on the primary queue and the pending timers
+// aren't part of a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot<SM, P> {
+    pub states: Vec<StateSnapshot>,
+    pub idx_current_state: usize,
+    pub idx_previous_state: usize,
+
+    // true with idxs_enter_fns the states to enter if the
+    // snapshot was taken before the executor was started
+    pub current_state_changed: bool,
+    pub idxs_enter_fns: Vec<usize>,
+
+    // The deferred messages of both defer queues
+    pub deferred: [Vec<DeferredSnapshot<P>>; 2],
+    pub current_defer_idx: usize,
+
+    // The seq of the next message, see MsgInfo
+    pub next_seq: u64,
+
+    pub sm: SM,
+}
+
+// Errors returned by Executor::restore, the executor is unchanged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+    // The snapshot and executor have a different number of states
+    StateCount {
+        snapshot: usize,
+        executor: usize,
+    },
+
+    // The state at idx has a different name, parent, parallel,
+    // history, is_final or initial_child
+    StateMismatch {
+        idx: usize,
+        snapshot: String,
+        executor: String,
+    },
+
+    // field has an index that isn't valid
+    OutOfRange {
+        field: &'static str,
+        idx: usize,
+    },
+}
+
+impl Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreError::StateCount { snapshot, executor } => write!(
+                f,
+                "The snapshot has {snapshot} states but the executor has {executor}"
+            ),
+            RestoreError::StateMismatch {
+                idx,
+                snapshot,
+                executor,
+            } => write!(
+                f,
+                "State {idx} is {snapshot} in the snapshot but {executor} in the executor"
+            ),
+            RestoreError::OutOfRange { field, idx } => {
+                write!(f, "The snapshot's {field} has an invalid index {idx}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+impl StateSnapshot {
+    fn new<SM, P, S>(state: &StateInfo<SM, P, S>) -> Self {
+        StateSnapshot {
+            name: state.name.clone(),
+            parent: state.parent,
+            parallel: state.parallel,
+            history: state.history,
+            is_final: state.is_final,
+            initial_child: state.initial_child,
+            active: state.active,
+            enter_cnt: state.enter_cnt,
+            process_cnt: state.process_cnt,
+            exit_cnt: state.exit_cnt,
+            last_active_child: state.last_active_child,
+        }
+    }
+
+    // Returns true if both describe the same state of a state table
+    fn same_table(&self, other: &StateSnapshot) -> bool {
+        self.name == other.name
+            && self.parent == other.parent
+            && self.parallel == other.parallel
+            && self.history == other.history
+            && self.is_final == other.is_final
+            && self.initial_child == other.initial_child
+    }
+
+    // How a state is described in RestoreError::StateMismatch
+    fn describe(&self) -> String {
+        let mut description = match self.parent {
+            Some(idx_parent) => format!("'{}' with parent {idx_parent}", self.name),
+            None => format!("'{}' with no parent", self.name),
+        };
+        if self.parallel {
+            description.push_str(", parallel");
+        }
+        if let Some(history) = self.history {
+            description.push_str(&format!(", history {history:?}"));
+        }
+        if self.is_final {
+            description.push_str(", final");
+        }
+        if let Some(idx_child) = self.initial_child {
+            description.push_str(&format!(", initial child {idx_child}"));
+        }
+
+        description
+    }
+}
+
+impl<SM, P, S> Executor<SM, P, S>
+where
+    SM: Debug,
+    P: Debug,
+    S: StateId,
+{
+    // Save the state of the executor, including its SM, so it can be
+    // restored by an executor built with the same state table.
+    pub fn snapshot(&self) -> Snapshot<SM, P>
+    where
+        SM: Clone,
+        P: Clone,
+    {
+        let states = self.states.iter().map(StateSnapshot::new).collect();
+
+        Snapshot {
+            states,
+            idx_current_state: self.idx_current_state,
+            idx_previous_state: self.idx_previous_state,
+            current_state_changed: self.current_state_changed,
+            idxs_enter_fns: self.idxs_enter_fns.clone(),
+            deferred: [self.deferred(0), self.deferred(1)],
+            current_defer_idx: self.current_defer_idx,
+            next_seq: self.primary_tx.next_seq(),
+            sm: self.sm.borrow().clone(),
+        }
+    }
+
+    // Restore a snapshot, returns an error if the executor's state
+    // table isn't the one the snapshot was taken from. The deferred
+    // messages of the executor are replaced by the snapshot's and
+    // the seqs of new messages continue after the snapshot's.
+    pub fn restore(&mut self, snapshot: Snapshot<SM, P>) -> Result<(), RestoreError> {
+        self.check_snapshot(&snapshot)?;
+
+        for (state, saved) in self.states.iter_mut().zip(snapshot.states.iter()) {
+            state.active = saved.active;
+            state.enter_cnt = saved.enter_cnt;
+            state.process_cnt = saved.process_cnt;
+            state.exit_cnt = saved.exit_cnt;
+            state.last_active_child = saved.last_active_child;
+        }
+        self.idx_current_state = snapshot.idx_current_state;
+        self.idx_previous_state = snapshot.idx_previous_state;
+        self.current_state_changed = snapshot.current_state_changed;
+        self.idxs_enter_fns = snapshot.idxs_enter_fns;
+        self.idxs_exit_fns.clear();
+        self.idx_transition_dest = None;
+        self.transition_dest_action = None;
+
+        // The deferred messages keep their seq and replays, their
+        // enqueued_at is rebased on the executor's clock
+        let now = self.clock.now();
+        for (queue, deferred) in self.deferred.get_mut().iter_mut().zip(snapshot.deferred) {
+            *queue = deferred
+                .into_iter()
+                .map(|saved| Deferred {
+                    msg: saved.msg,
+                    info: MsgInfo {
+                        seq: saved.seq,
+                        enqueued_at: now.checked_sub(saved.age).unwrap_or(now),
+                    },
+                    replays: saved.replays,
+                })
+                .collect();
+        }
+        self.primary_tx.advance_seq(snapshot.next_seq);
+        self.current_defer_idx = snapshot.current_defer_idx;
+        *self.sm.borrow_mut() = snapshot.sm;
+
+        Ok(())
+    }
+
+    fn check_snapshot(&self, snapshot: &Snapshot<SM, P>) -> Result<(), RestoreError> {
+        if snapshot.states.len() != self.states.len() {
+            return Err(RestoreError::StateCount {
+                snapshot: snapshot.states.len(),
+                executor: self.states.len(),
+            });
+        }
+        for (idx, (state, saved)) in self.states.iter().zip(snapshot.states.iter()).enumerate() {
+            let state = StateSnapshot::new(state);
+            if !state.same_table(saved) {
+                return Err(RestoreError::StateMismatch {
+                    idx,
+                    snapshot: saved.describe(),
+                    executor: state.describe(),
+                });
+            }
+        }
+
+        let len = self.states.len();
+        let idxs = [
+            ("idx_current_state", snapshot.idx_current_state, len),
+            ("idx_previous_state", snapshot.idx_previous_state, len),
+            ("current_defer_idx", snapshot.current_defer_idx, 2),
+        ]
+        .into_iter()
+        .chain(
+            snapshot
+                .idxs_enter_fns
+                .iter()
+                .map(|idx| ("idxs_enter_fns", *idx, len)),
+        )
+        .chain(
+            snapshot
+                .states
+                .iter()
+                .filter_map(|state| state.last_active_child)
+                .map(|idx| ("last_active_child", idx, len)),
+        );
+        for (field, idx, len) in idxs {
+            if idx >= len {
+                return Err(RestoreError::OutOfRange { field, idx });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Copy the messages of a defer queue leaving them in the queue
+    fn deferred(&self, idx: usize) -> Vec<DeferredSnapshot<P>>
+    where
+        P: Clone,
+    {
+        let now = self.clock.now();
+        self.deferred.borrow()[idx]
+            .iter()
+            .map(|deferred| DeferredSnapshot {
+                msg: deferred.msg.clone(),
+                seq: deferred.info.seq,
+                age: now.saturating_duration_since(deferred.info.enqueued_at),
+                replays: deferred.replays,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, sync::Arc};
+
+    use super::*;
+    use crate::{Handled, ManualClock, StateInfo, StateResult};
+
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    enum Messages {
+        Start,
+        Open,
+        Data { val: u32 },
+        Close,
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    struct StateMachine {
+        sum: u32,
+    }
+
+    //      base
+    //     /    \
+    // closed    open
+    const MAX_STATES: usize = 3;
+    const IDX_BASE: usize = 0;
+    const IDX_CLOSED: usize = 1;
+    const IDX_OPEN: usize = 2;
+
+    impl StateMachine {
+        #[no_coverage]
+        fn new(open_name: &str) -> Executor<Self, Messages> {
+            let sm = RefCell::new(StateMachine::default());
+            Executor::new(sm, MAX_STATES)
+                .state(StateInfo::new("base", Self::base))
+                .state(StateInfo::new("closed", Self::closed).parent_idx(IDX_BASE))
+                .state(StateInfo::new(open_name, Self::open).parent_idx(IDX_BASE))
+                .clock(Arc::new(ManualClock::new()))
+                .build(IDX_CLOSED)
+                .expect("Unexpected error initializing")
+        }
+
+        #[no_coverage]
+        fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+            (Handled::Yes, None)
+        }
+
+        #[no_coverage]
+        fn closed(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+            match msg {
+                Messages::Open => (Handled::Yes, Some(IDX_OPEN)),
+                Messages::Data { .. } => {
+                    e.defer_current(msg.clone()).unwrap();
+                    (Handled::Yes, None)
+                }
+                _ => (Handled::No, None),
+            }
+        }
+
+        #[no_coverage]
+        fn open(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+            match msg {
+                Messages::Data { val } => {
+                    self.sum += val;
+                    (Handled::Yes, None)
+                }
+                Messages::Close => (Handled::Yes, Some(IDX_CLOSED)),
+                _ => (Handled::No, None),
+            }
+        }
+    }
+
+    #[no_coverage]
+    fn dispatch_all(sme: &mut Executor<StateMachine, Messages>) {
+        sme.dispatcher(&Messages::Data { val: 1 }).unwrap();
+        sme.dispatcher(&Messages::Open).unwrap();
+        sme.dispatcher(&Messages::Data { val: 2 }).unwrap();
+        sme.dispatcher(&Messages::Close).unwrap();
+        sme.dispatcher(&Messages::Data { val: 3 }).unwrap();
+    }
+
+    #[no_coverage]
+    fn assert_same(a: &Executor<StateMachine, Messages>, b: &Executor<StateMachine, Messages>) {
+        assert_eq!(a.snapshot(), b.snapshot());
+        for idx in 0..MAX_STATES {
+            assert_eq!(a.get_state_enter_cnt(idx), b.get_state_enter_cnt(idx));
+            assert_eq!(a.get_state_process_cnt(idx), b.get_state_process_cnt(idx));
+            assert_eq!(a.get_state_exit_cnt(idx), b.get_state_exit_cnt(idx));
+        }
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_snapshot_restore() {
+        let mut sme = StateMachine::new("open");
+        sme.start_with(&Messages::Start);
+        dispatch_all(&mut sme);
+        assert_eq!(sme.get_current_state_name(), "closed");
+
+        let snapshot = sme.snapshot();
+        // Data 3 was deferred with the seq it was dispatched with
+        assert_eq!(
+            snapshot.deferred.concat(),
+            vec![DeferredSnapshot {
+                msg: Messages::Data { val: 3 },
+                seq: 4,
+                age: Duration::ZERO,
+                replays: 0,
+            }]
+        );
+        assert_eq!(snapshot.next_seq, 5);
+        assert_eq!(snapshot.sm, StateMachine { sum: 3 });
+        assert!(!snapshot.current_state_changed);
+
+        // Taking a snapshot doesn't change the executor
+        assert_eq!(sme.snapshot(), snapshot);
+
+        let mut restored = StateMachine::new("open");
+        restored.restore(snapshot).unwrap();
+        assert_same(&sme, &restored);
+
+        // The deferred Data is processed after the next Open
+        for e in [&mut sme, &mut restored] {
+            e.dispatcher(&Messages::Open).unwrap();
+            assert_eq!(e.get_current_state_name(), "open");
+            assert_eq!(e.get_sm().borrow().sum, 6);
+        }
+        assert_same(&sme, &restored);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_snapshot_before_start() {
+        let sme = StateMachine::new("open");
+        let mut restored = StateMachine::new("open");
+        restored.dispatcher(&Messages::Open).unwrap();
+        restored.restore(sme.snapshot()).unwrap();
+
+        restored.start_with(&Messages::Start);
+        assert_eq!(restored.get_current_state_name(), "closed");
+        let active: Vec<bool> = restored.states.iter().map(|s| s.active).collect();
+        assert_eq!(active, vec![true, true, false]);
+        assert_eq!(restored.get_state_process_cnt(IDX_OPEN), 0);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_restore_mismatch() {
+        let mut sme = StateMachine::new("open");
+        sme.start_with(&Messages::Start);
+        let mut snapshot = sme.snapshot();
+
+        let mut other = StateMachine::new("opened");
+        other.start_with(&Messages::Start);
+        other.dispatcher(&Messages::Open).unwrap();
+        let before = other.snapshot();
+        assert_eq!(
+            other.restore(snapshot.clone()),
+            Err(RestoreError::StateMismatch {
+                idx: IDX_OPEN,
+                snapshot: "'open' with parent 0".to_owned(),
+                executor: "'opened' with parent 0".to_owned(),
+            })
+        );
+        assert_eq!(other.snapshot(), before);
+
+        // A state that became parallel
+        let mut parallel = snapshot.clone();
+        parallel.states[IDX_BASE].parallel = true;
+        assert_eq!(
+            sme.restore(parallel),
+            Err(RestoreError::StateMismatch {
+                idx: IDX_BASE,
+                snapshot: "'base' with no parent, parallel".to_owned(),
+                executor: "'base' with no parent".to_owned(),
+            })
+        );
+
+        snapshot.idx_current_state = MAX_STATES;
+        assert_eq!(
+            sme.restore(snapshot.clone()),
+            Err(RestoreError::OutOfRange {
+                field: "idx_current_state",
+                idx: MAX_STATES,
+            })
+        );
+
+        snapshot.states.pop();
+        assert_eq!(
+            sme.restore(snapshot),
+            Err(RestoreError::StateCount {
+                snapshot: MAX_STATES - 1,
+                executor: MAX_STATES,
+            })
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    #[no_coverage]
+    fn test_snapshot_serde() {
+        let mut sme = StateMachine::new("open");
+        sme.start_with(&Messages::Start);
+        dispatch_all(&mut sme);
+
+        let json = serde_json::to_string(&sme.snapshot()).unwrap();
+        let mut restored = StateMachine::new("open");
+        restored
+            .restore(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        assert_same(&sme, &restored);
+    }
+}
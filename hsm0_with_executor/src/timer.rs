@@ -0,0 +1,252 @@
+use std::{
+    fmt::{self, Display},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// The source of time for timers, inject a ManualClock
+// with Executor::clock to control time in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+// The default clock, Instant::now()
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// A clock that only changes when advanced, clones share the same time
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+// Errors returned when starting a timer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimerError {
+    // A periodic timer's period is zero, it would never stop expiring
+    ZeroPeriod,
+}
+
+impl Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimerError::ZeroPeriod => write!(f, "A periodic timer's period must not be zero"),
+        }
+    }
+}
+
+impl std::error::Error for TimerError {}
+
+// Identifies a timer, returned by Executor::send_after and friends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+// The period of a periodic timer and the fn that
+// clones the msg sent each time it expires
+type Period<P> = (Duration, fn(&P) -> P);
+
+struct Timer<P> {
+    id: TimerId,
+    deadline: Instant,
+
+    // Some for periodic timers
+    period: Option<Period<P>>,
+
+    // The index of the state that owns the timer, if any
+    idx_owner: Option<usize>,
+
+    msg: P,
+}
+
+// The pending timers of an Executor
+pub(crate) struct Timers<P> {
+    next_id: u64,
+    timers: Vec<Timer<P>>,
+
+    // The id and owner of each expired timer whose msg is on
+    // the primary queue, one entry per msg
+    in_flight: Vec<(TimerId, Option<usize>)>,
+}
+
+impl<P> Timers<P> {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_id: 0,
+            timers: Vec::new(),
+            in_flight: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add(
+        &mut self,
+        deadline: Instant,
+        period: Option<Period<P>>,
+        idx_owner: Option<usize>,
+        msg: P,
+    ) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        self.timers.push(Timer {
+            id,
+            deadline,
+            period,
+            idx_owner,
+            msg,
+        });
+
+        id
+    }
+
+    // Returns true if the timer was pending or it expired and
+    // its msg hasn't been received, the msg is then dropped
+    pub(crate) fn cancel(&mut self, id: TimerId) -> bool {
+        let len = self.timers.len() + self.in_flight.len();
+        self.timers.retain(|timer| timer.id != id);
+        self.in_flight.retain(|(in_flight, _)| *in_flight != id);
+
+        self.timers.len() + self.in_flight.len() != len
+    }
+
+    // Move the deadlines from a clock whose time is from to one
+    // whose time is to, keeping the time remaining until each expires
+    pub(crate) fn rebase(&mut self, from: Instant, to: Instant) {
+        for timer in self.timers.iter_mut() {
+            timer.deadline = to + timer.deadline.saturating_duration_since(from);
+        }
+    }
+
+    // Cancel the timers owned by the state at idx, including
+    // those whose msg hasn't been received
+    pub(crate) fn cancel_owned_by(&mut self, idx: usize) {
+        self.timers.retain(|timer| timer.idx_owner != Some(idx));
+        self.in_flight
+            .retain(|(_, idx_owner)| *idx_owner != Some(idx));
+    }
+
+    // Called when the msg of timer id is received, returns
+    // false if the timer was cancelled and the msg is dropped
+    pub(crate) fn received(&mut self, id: TimerId) -> bool {
+        match self
+            .in_flight
+            .iter()
+            .position(|(in_flight, _)| *in_flight == id)
+        {
+            Some(idx) => {
+                self.in_flight.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().map(|timer| timer.deadline).min()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    // Remove the timers that have expired at now and return their
+    // ids and msgs in deadline order, timers with the same deadline
+    // are in the order they were added. Periodic timers are rearmed
+    // and a periodic timer that has expired more than once is only
+    // returned once. The msgs are in flight until received.
+    pub(crate) fn expired(&mut self, now: Instant) -> Vec<(TimerId, P)> {
+        let mut expired = Vec::new();
+        let mut idx = 0;
+        while idx < self.timers.len() {
+            if self.timers[idx].deadline > now {
+                idx += 1;
+                continue;
+            }
+
+            let timer = &mut self.timers[idx];
+            self.in_flight.push((timer.id, timer.idx_owner));
+            if let Some((period, clone_fn)) = timer.period {
+                expired.push((timer.deadline, timer.id, clone_fn(&timer.msg)));
+                while timer.deadline <= now {
+                    timer.deadline += period;
+                }
+                idx += 1;
+            } else {
+                let timer = self.timers.remove(idx);
+                expired.push((timer.deadline, timer.id, timer.msg));
+            }
+        }
+        expired.sort_by_key(|(deadline, id, _)| (*deadline, *id));
+
+        expired.into_iter().map(|(_, id, msg)| (id, msg)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[no_coverage]
+    fn test_timers_expired() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut timers = Timers::<u32>::new();
+
+        let second = Duration::from_secs(1);
+        let id2 = timers.add(start + 2 * second, None, None, 2);
+        let id = timers.add(start + second, None, None, 1);
+        let id10 = timers.add(start + second, Some((3 * second, u32::clone)), Some(0), 10);
+        assert_eq!(timers.next_deadline(), Some(start + second));
+        assert!(timers.expired(clock.now()).is_empty());
+
+        clock.advance(second);
+        assert_eq!(timers.expired(clock.now()), vec![(id, 1), (id10, 10)]);
+        assert!(timers.received(id));
+        assert!(timers.received(id10));
+        assert!(!timers.received(id));
+        assert!(!timers.cancel(id));
+        assert_eq!(timers.next_deadline(), Some(start + 2 * second));
+
+        clock.advance(10 * second);
+        assert_eq!(timers.expired(clock.now()), vec![(id2, 2), (id10, 10)]);
+        assert_eq!(timers.len(), 1);
+        assert_eq!(timers.next_deadline(), Some(start + 13 * second));
+
+        // Cancelling drops the msgs that haven't been received
+        assert!(timers.cancel(id2));
+        assert!(!timers.received(id2));
+        timers.cancel_owned_by(0);
+        assert!(!timers.received(id10));
+        assert_eq!(timers.len(), 0);
+        assert_eq!(timers.next_deadline(), None);
+    }
+}
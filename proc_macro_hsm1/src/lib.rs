@@ -32,6 +32,10 @@ struct Hsm1 {
     #[allow(unused)]
     hsm_state_fn_ident_map: HashMap<String, usize>,
     hsm_state_fn_idents: Vec<StateFnIdents>,
+
+    // A fn named `unhandled` was defined, it's invoked with
+    // the messages the top most state returned NotHandled for
+    has_unhandled_fn: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -271,6 +275,12 @@ impl Parse for Hsm1 {
             });
         }
 
+        let has_unhandled_fn = fn_map.contains_key("unhandled");
+        if has_unhandled_fn && state_fn_idents_map.contains_key("unhandled") {
+            // TODO: Improve error handling
+            panic!("unhandled is reserved for the unhandled message fn, it may not be a state");
+        }
+
         //println!("hsm1::parse:-");
         Ok(Hsm1 {
             hsm_ident: item_struct.ident.clone(),
@@ -278,6 +288,7 @@ impl Parse for Hsm1 {
             hsm_fns: fns,
             hsm_state_fn_ident_map: state_fn_idents_map,
             hsm_state_fn_idents: state_fn_idents,
+            has_unhandled_fn,
         })
     }
 }
@@ -423,6 +434,12 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
     let hsm_state_fn_ident_map = hsm.hsm_state_fn_ident_map;
     //println!("hsm1: hsm_state_fn_ident_map={:?}", _hsm_state_fn_ident_map);
 
+    let unhandled_call = if hsm.has_unhandled_fn {
+        quote!(self.unhandled(msg);)
+    } else {
+        quote!()
+    };
+
     let state_fn = new_ident(hsm_ident.clone(), "StateFn");
     let state_fn_enter = new_ident(hsm_ident.clone(), "StateFnEnter");
     let state_fn_exit = new_ident(hsm_ident.clone(), "StateFnExit");
@@ -637,11 +654,97 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
                 }
             }
 
+            // Returns true if hdl is the current state or one of its parents
+            fn is_current_or_parent(&self, hdl: usize) -> bool {
+                let mut cur_hdl = Some(self.smi.current_state_fns_hdl);
+                while let Some(h) = cur_hdl {
+                    if h == hdl {
+                        return true;
+                    }
+                    cur_hdl = self.smi.state_fns[h].parent;
+                }
+
+                false
+            }
+
             // TODO: Not sure this is worth it, if it is consider adding hsm_name()
             fn state_name(&self) -> &str {
                 &self.smi.state_fns[self.smi.current_state_fns_hdl].name
             }
 
+            // The state hierarchy marking the initial and current states
+            #[allow(unused)]
+            pub fn diagram(&self) -> state_result::diagram::Diagram<'_> {
+                self.smi
+                    .state_fns
+                    .iter()
+                    .fold(
+                        state_result::diagram::Diagram::new(#initial_state_hdl),
+                        |diagram, state| diagram.state(&state.name, state.parent),
+                    )
+                    .current(self.smi.current_state_fns_hdl)
+            }
+
+            // Graphviz DOT
+            #[allow(unused)]
+            pub fn to_dot(&self) -> String {
+                self.diagram().to_dot()
+            }
+
+            // Mermaid stateDiagram-v2
+            #[allow(unused)]
+            pub fn to_mermaid(&self) -> String {
+                self.diagram().to_mermaid()
+            }
+
+            #[allow(unused)]
+            pub fn to_plantuml(&self) -> String {
+                self.diagram().to_plantuml()
+            }
+
+            // The number of messages the top most state returned NotHandled
+            // for, each is also passed to `fn unhandled` if it's defined
+            #[allow(unused)]
+            pub fn unhandled_cnt(&self) -> usize {
+                self.smi.unhandled_cnt
+            }
+
+            // Keep the last capacity unhandled messages as DeadLetters, see
+            // take_dead_letters. The default is 0, none are kept.
+            #[allow(unused)]
+            pub fn dead_letter_capacity(mut self, capacity: usize) -> Self {
+                self.smi.dead_letter_capacity = capacity;
+                self.smi.dead_letters = std::collections::VecDeque::with_capacity(capacity);
+
+                self
+            }
+
+            // The number of DeadLetters dropped because there were
+            // already dead_letter_capacity of them
+            #[allow(unused)]
+            pub fn dead_letters_dropped(&self) -> usize {
+                self.smi.dead_letters_dropped
+            }
+
+            // Remove and return the DeadLetters, oldest first
+            #[allow(unused)]
+            pub fn take_dead_letters(&mut self) -> Vec<state_result::DeadLetter> {
+                self.smi.dead_letters.drain(..).collect()
+            }
+
+            // Keep a DeadLetter for the current state
+            fn dead_letter(&mut self) {
+                if self.smi.dead_letter_capacity > 0 {
+                    if self.smi.dead_letters.len() == self.smi.dead_letter_capacity {
+                        self.smi.dead_letters.pop_front();
+                        self.smi.dead_letters_dropped += 1;
+                    }
+                    let hdl = self.smi.current_state_fns_hdl;
+                    let state = self.state_name().to_owned();
+                    self.smi.dead_letters.push_back(state_result::DeadLetter { hdl, state });
+                }
+            }
+
             fn dispatch_hdl(&mut self, msg: #state_fn_msg_type, hdl: usize) {
                 //println!("dispatch_hdl {}:+", hdl);
                 if self.smi.current_state_changed && !self.smi.enter_fns_hdls.is_empty() {
@@ -671,8 +774,10 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
                             self.dispatch_hdl(msg, parent_hdl);
                             //println!("dispatch_hdl {}: retf process, NotHandled, retf dispatch_hdl({})", hdl, parent_hdl);
                         } else {
-                            // TODO: Consider calling a "default_handler" when NotHandled and no parent
                             //println!("dispatch_hdl {}: retf process, NotHandled no parent", hdl);
+                            self.smi.unhandled_cnt += 1;
+                            #unhandled_call
+                            self.dead_letter();
                         }
                     }
                     state_result::StateResult::Handled => {
@@ -685,6 +790,14 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
                         self.smi.current_state_changed = true;
                         transition_dest_hdl = Some(dest_hdl);
                     }
+                    state_result::StateResult::InternalTransitionTo(dest_hdl) => {
+                        //println!("dispatch_hdl {}: retf process, InternalTransitionTo({})", hdl, dest_hdl);
+                        if !self.is_current_or_parent(dest_hdl) {
+                            self.setup_exit_enter_fns_hdls(dest_hdl);
+                            self.smi.current_state_changed = true;
+                            transition_dest_hdl = Some(dest_hdl);
+                        }
+                    }
                 }
 
                 if self.smi.current_state_changed && !self.smi.exit_fns_hdls.is_empty() {
@@ -739,6 +852,10 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
             current_state_fns_hdl: state_result::StateFnsHdl,
             previous_state_fns_hdl: state_result::StateFnsHdl,
             current_state_changed: bool,
+            unhandled_cnt: usize,
+            dead_letters: std::collections::VecDeque<state_result::DeadLetter>,
+            dead_letter_capacity: usize,
+            dead_letters_dropped: usize,
         }
 
         impl Default for #state_machine_info {
@@ -760,6 +877,10 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
                     current_state_fns_hdl: #initial_state_hdl,
                     previous_state_fns_hdl: #initial_state_hdl,
                     current_state_changed: true,
+                    unhandled_cnt: 0,
+                    dead_letters: std::collections::VecDeque::new(),
+                    dead_letter_capacity: 0,
+                    dead_letters_dropped: 0,
                 }
             }
         }
@@ -778,6 +899,14 @@ pub fn transition_to(item: TokenStream) -> TokenStream {
     quote!(state_result::StateResult::TransitionTo(#item_ts2)).into()
 }
 
+#[proc_macro]
+pub fn internal_transition_to(item: TokenStream) -> TokenStream {
+    let item_ts2: TokenStream2 = item.into();
+    //println!("proc_macro internal_transition_to!: item_ts2={:?}", item_ts2);
+
+    quote!(state_result::StateResult::InternalTransitionTo(#item_ts2)).into()
+}
+
 #[proc_macro]
 pub fn handled(_item: TokenStream) -> TokenStream {
     //println!("proc_macro handled!: item={:?}", item);
@@ -812,19 +941,21 @@ struct Visitor {
 impl VisitMut for Visitor {
     // Invoke visit_item_fn_mut which will invoke vist_macro_mut for
     // each macro in the funtion. The code here will convert each
-    // transtion_to!(state_fn_name) to transition_to!(state_fn_index).
+    // transtion_to!(state_fn_name) to transition_to!(state_fn_index),
+    // and the same for internal_transition_to!.
     fn visit_macro_mut(&mut self, node: &mut Macro) {
         if let Some(ident_segment) = node.path.segments.last() {
             // The last segment is the name of the macro
-            if ident_segment.ident == "transition_to" {
-                // Found our macro, transition_to
+            let macro_name = ident_segment.ident.to_string();
+            if macro_name == "transition_to" || macro_name == "internal_transition_to" {
+                // Found one of our macros, transition_to or internal_transition_to
 
                 // Get the first token; aka: parameter to the function
                 let mut iter = node.tokens.clone().into_iter();
                 if let Some(token) = iter.next() {
                     if iter.next().is_some() {
                         // TODO: improve error handling
-                        panic!("transition_to! may have only one parameter, the name of the state")
+                        panic!("{macro_name}! may have only one parameter, the name of the state")
                     }
                     let parameter = token.to_string();
                     if let Some(hdl) = self.hsm_state_fn_ident_map.get(&parameter) {
@@ -836,7 +967,7 @@ impl VisitMut for Visitor {
                     }
                 } else {
                     // TODO: improve error handling
-                    panic!("transition_to! must have one parameter, the name of the state")
+                    panic!("{macro_name}! must have one parameter, the name of the state")
                 }
             }
         }
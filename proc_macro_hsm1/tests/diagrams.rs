@@ -0,0 +1,48 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to, StateResult};
+
+struct NoMessages;
+
+hsm1!(
+    struct Diagrams {}
+
+    #[hsm1_state]
+    fn base(&mut self, _msg: &NoMessages) -> StateResult!() {
+        handled!()
+    }
+
+    #[hsm1_initial_state(base)]
+    fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+        transition_to!(other)
+    }
+
+    #[hsm1_state(base)]
+    fn other(&mut self, _msg: &NoMessages) -> StateResult!() {
+        handled!()
+    }
+);
+
+#[test]
+fn test_diagrams() {
+    let mut hsm = Diagrams::new();
+    assert_eq!(
+        hsm.to_mermaid(),
+        r##"stateDiagram-v2
+    [*] --> s0
+    state "base" as s0
+    state s0 {
+        [*] --> s1
+        state "initial" as s1
+        state "other" as s2
+    }
+    classDef current fill:#ffcc66
+    class s1 current
+"##
+    );
+
+    hsm.dispatch(&NoMessages);
+    assert!(hsm.to_dot().contains("s2 [label=\"other\", style="));
+    assert!(hsm.to_dot().contains("initial -> s1;"));
+    assert!(hsm
+        .to_plantuml()
+        .contains("  state \"other\" as s2 #ffcc66\n"));
+}
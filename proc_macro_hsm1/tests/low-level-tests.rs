@@ -1143,3 +1143,87 @@ fn test_initial_then_tree_of_parent_and_do_work_then_done() {
     assert_eq!(hsm.done_cnt, 2);
     assert_eq!(hsm.done_exit_cnt, 0);
 }
+
+#[test]
+fn test_unhandled() {
+    hsm1!(
+        struct TestUnhandled {
+            unhandled_states: Vec<String>,
+        }
+
+        #[hsm1_state]
+        fn base(&mut self, _msg: &NoMessages) -> StateResult!() {
+            not_handled!()
+        }
+
+        #[hsm1_initial_state(base)]
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+            not_handled!()
+        }
+
+        fn unhandled(&mut self, _msg: &NoMessages) {
+            let state_name = self.state_name().to_owned();
+            self.unhandled_states.push(state_name);
+        }
+    );
+
+    hsm1!(
+        struct TestNoUnhandledFn {}
+
+        #[hsm1_initial_state]
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+            not_handled!()
+        }
+    );
+
+    let mut hsm = TestUnhandled::new();
+    assert_eq!(hsm.unhandled_cnt(), 0);
+    hsm.dispatch(&NoMessages);
+    hsm.dispatch(&NoMessages);
+    assert_eq!(hsm.unhandled_cnt(), 2);
+    assert_eq!(hsm.unhandled_states, vec!["initial", "initial"]);
+
+    // Without an unhandled fn the messages are only counted
+    let mut fsm = TestNoUnhandledFn::new();
+    fsm.dispatch(&NoMessages);
+    assert_eq!(fsm.unhandled_cnt(), 1);
+}
+
+#[test]
+fn test_dead_letters() {
+    hsm1!(
+        struct TestDeadLetters {}
+
+        #[hsm1_initial_state]
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+            transition_to!(other)
+        }
+
+        #[hsm1_state]
+        fn other(&mut self, _msg: &NoMessages) -> StateResult!() {
+            not_handled!()
+        }
+    );
+
+    // By default no DeadLetters are kept
+    let mut fsm = TestDeadLetters::new();
+    fsm.dispatch(&NoMessages);
+    fsm.dispatch(&NoMessages);
+    assert_eq!(fsm.unhandled_cnt(), 1);
+    assert!(fsm.take_dead_letters().is_empty());
+
+    // Only the last capacity are kept
+    let mut fsm = TestDeadLetters::new().dead_letter_capacity(2);
+    fsm.dispatch(&NoMessages);
+    for _ in 0..4 {
+        fsm.dispatch(&NoMessages);
+    }
+    assert_eq!(fsm.unhandled_cnt(), 4);
+    assert_eq!(fsm.dead_letters_dropped(), 2);
+    let other = state_result::DeadLetter {
+        hdl: 1,
+        state: "other".to_owned(),
+    };
+    assert_eq!(fsm.take_dead_letters(), vec![other.clone(), other]);
+    assert!(fsm.take_dead_letters().is_empty());
+}
@@ -1,5 +1,8 @@
 //use hsm1::{handled, hsm1, hsm1_state, not_handled, transition_to, StateResult};
-use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to, StateResult};
+use proc_macro_hsm1::{
+    handled, hsm1, hsm1_initial_state, hsm1_state, internal_transition_to, transition_to,
+    StateResult,
+};
 
 struct NoMessages;
 
@@ -304,3 +307,74 @@ fn test_transitions_between_leafs_of_trees() {
     assert_eq!(sm.other_cnt, 2);
     assert_eq!(sm.other_exit_cnt, 2);
 }
+
+#[test]
+fn test_internal_and_external_transitions() {
+    enum Messages {
+        Tick,
+        SelfExternal,
+        SelfInternal,
+        BaseExternal,
+        BaseInternal,
+    }
+
+    hsm1!(
+        struct Test {
+            log: Vec<&'static str>,
+        }
+
+        fn base_enter(&mut self, _msg: &Messages) {
+            self.log.push("+base");
+        }
+
+        #[hsm1_state]
+        fn base(&mut self, _msg: &Messages) -> StateResult!() {
+            handled!()
+        }
+
+        fn base_exit(&mut self, _msg: &Messages) {
+            self.log.push("-base");
+        }
+
+        fn initial_enter(&mut self, _msg: &Messages) {
+            self.log.push("+initial");
+        }
+
+        #[hsm1_initial_state(base)]
+        fn initial(&mut self, msg: &Messages) -> StateResult!() {
+            match msg {
+                Messages::Tick => handled!(),
+                Messages::SelfExternal => transition_to!(initial),
+                Messages::SelfInternal => internal_transition_to!(initial),
+                Messages::BaseExternal => transition_to!(base),
+                Messages::BaseInternal => internal_transition_to!(base),
+            }
+        }
+
+        fn initial_exit(&mut self, _msg: &Messages) {
+            self.log.push("-initial");
+        }
+    );
+
+    let mut sm = Test::new();
+    sm.dispatch(&Messages::Tick);
+    assert_eq!(sm.log, vec!["+base", "+initial"]);
+
+    // An external self transition exits and enters the state
+    sm.log.clear();
+    sm.dispatch(&Messages::SelfExternal);
+    sm.dispatch(&Messages::Tick);
+    assert_eq!(sm.log, vec!["-initial", "+initial"]);
+
+    // Internal transitions to the state or a parent exit and enter nothing
+    sm.log.clear();
+    sm.dispatch(&Messages::SelfInternal);
+    sm.dispatch(&Messages::BaseInternal);
+    sm.dispatch(&Messages::Tick);
+    assert!(sm.log.is_empty());
+
+    // An external transition to a parent exits and enters it
+    sm.dispatch(&Messages::BaseExternal);
+    sm.dispatch(&Messages::Tick);
+    assert_eq!(sm.log, vec!["-initial", "-base", "+base"]);
+}
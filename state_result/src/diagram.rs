@@ -0,0 +1,453 @@
+// Render a state hierarchy as a diagram, used by hsm1 and
+// hsm0_with_executor so both produce the same output.
+//
+// States with children are drawn as clusters containing their
+// children, the regions of parallel states are separated by `--`
+// and in DOT parallel states are dashed. The initial state is
+// marked with the usual `[*]` pseudo-state, in DOT a point, and
+// the current state is filled. Transitions are labeled edges.
+use std::fmt::Write;
+
+// Fill color of the current state
+const CURRENT_COLOR: &str = "#ffcc66";
+
+#[derive(Debug, Clone)]
+pub struct Diagram<'a> {
+    names: Vec<&'a str>,
+    parents: Vec<Option<usize>>,
+    parallel: Vec<bool>,
+    initial_children: Vec<Option<usize>>,
+    transitions: Vec<(usize, usize, &'a str)>,
+    idx_initial: usize,
+    idx_current: Option<usize>,
+}
+
+impl<'a> Diagram<'a> {
+    pub fn new(idx_initial: usize) -> Self {
+        Self {
+            names: Vec::new(),
+            parents: Vec::new(),
+            parallel: Vec::new(),
+            initial_children: Vec::new(),
+            transitions: Vec::new(),
+            idx_initial,
+            idx_current: None,
+        }
+    }
+
+    // Add the next state, states are identified by the order added
+    pub fn state(mut self, name: &'a str, parent: Option<usize>) -> Self {
+        self.names.push(name);
+        self.parents.push(parent);
+        self.parallel.push(false);
+        self.initial_children.push(None);
+
+        self
+    }
+
+    // Add the next state, its children are its regions
+    pub fn parallel_state(mut self, name: &'a str, parent: Option<usize>) -> Self {
+        self = self.state(name, parent);
+        *self.parallel.last_mut().expect("SNH, state was added") = true;
+
+        self
+    }
+
+    // The child the last state added is entered at, the
+    // default is its first child
+    pub fn initial_child(mut self, idx_child: usize) -> Self {
+        *self
+            .initial_children
+            .last_mut()
+            .expect("SNH, no state added") = Some(idx_child);
+
+        self
+    }
+
+    // Add a transition from idx_src to idx_dst, either may have children
+    pub fn transition(mut self, idx_src: usize, idx_dst: usize, label: &'a str) -> Self {
+        self.transitions.push((idx_src, idx_dst, label));
+
+        self
+    }
+
+    pub fn current(mut self, idx_current: usize) -> Self {
+        self.idx_current = Some(idx_current);
+
+        self
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out =
+            String::from("digraph {\n    compound=true;\n    node [shape=box, style=rounded];\n");
+        for idx in self.children(None) {
+            self.dot_state(&mut out, idx, 1);
+        }
+        if self.idx_initial < self.names.len() {
+            out.push_str("    initial [shape=point, width=0.15];\n");
+            let _ = writeln!(out, "    initial -> s{};", self.idx_initial);
+        }
+        for (idx_src, idx_dst, label) in self.transitions.iter().copied() {
+            // Edges connect nodes, a cluster is clipped to with ltail or lhead
+            let label = dot_escape(label);
+            let _ = write!(
+                out,
+                "    s{} -> s{} [label=\"{label}\"",
+                self.idx_leaf(idx_src),
+                self.idx_leaf(idx_dst)
+            );
+            if self.idx_leaf(idx_src) != idx_src {
+                let _ = write!(out, ", ltail=cluster_s{idx_src}");
+            }
+            if self.idx_leaf(idx_dst) != idx_dst {
+                let _ = write!(out, ", lhead=cluster_s{idx_dst}");
+            }
+            out.push_str("];\n");
+        }
+        out.push_str("}\n");
+
+        out
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("stateDiagram-v2\n");
+        self.mermaid_states(&mut out, None, 1);
+        for (idx_src, idx_dst, label) in self.transitions.iter() {
+            let label = mermaid_escape(label);
+            let _ = writeln!(out, "    s{idx_src} --> s{idx_dst} : {label}");
+        }
+        if let Some(idx) = self.idx_current {
+            let _ = writeln!(out, "    classDef current fill:{CURRENT_COLOR}");
+            let _ = writeln!(out, "    class s{idx} current");
+        }
+
+        out
+    }
+
+    pub fn to_plantuml(&self) -> String {
+        let mut out = String::from("@startuml\n");
+        self.plantuml_states(&mut out, None, 0);
+        for (idx_src, idx_dst, label) in self.transitions.iter() {
+            let label = plantuml_escape(label);
+            let _ = writeln!(out, "s{idx_src} --> s{idx_dst} : {label}");
+        }
+        out.push_str("@enduml\n");
+
+        out
+    }
+
+    fn children(&self, parent: Option<usize>) -> Vec<usize> {
+        (0..self.names.len())
+            .filter(|idx| self.parents[*idx] == parent)
+            .collect()
+    }
+
+    // The target of parent's `[*]`, the child that is, or is an ancestor
+    // of, the initial state. Otherwise its initial child or the first
+    // child as that's where it's entered. Parallel states have none as
+    // all their children are entered.
+    fn entry_child(&self, parent: Option<usize>) -> Option<usize> {
+        if let Some(idx_parent) = parent {
+            if self.parallel[idx_parent] {
+                return None;
+            }
+        }
+
+        let mut idx = Some(self.idx_initial).filter(|idx| *idx < self.names.len());
+        while let Some(i) = idx {
+            if self.parents[i] == parent {
+                return Some(i);
+            }
+            idx = self.parents[i];
+        }
+
+        parent.and_then(|idx_parent| {
+            self.initial_children[idx_parent].or_else(|| self.children(parent).first().copied())
+        })
+    }
+
+    // The first leaf at or below idx
+    fn idx_leaf(&self, mut idx: usize) -> usize {
+        while let Some(idx_child) = self.children(Some(idx)).first() {
+            idx = *idx_child;
+        }
+
+        idx
+    }
+
+    // Whether the children of parent are regions
+    fn is_parallel(&self, parent: Option<usize>) -> bool {
+        parent.is_some_and(|idx| self.parallel[idx])
+    }
+
+    fn dot_state(&self, out: &mut String, idx: usize, depth: usize) {
+        let indent = "    ".repeat(depth);
+        let name = dot_escape(self.names[idx]);
+        let children = self.children(Some(idx));
+        if children.is_empty() {
+            let fill = match self.idx_current {
+                Some(idx_current) if idx_current == idx => {
+                    format!(", style=\"rounded,filled\", fillcolor=\"{CURRENT_COLOR}\"")
+                }
+                _ => String::new(),
+            };
+            let _ = writeln!(out, "{indent}s{idx} [label=\"{name}\"{fill}];");
+        } else {
+            let _ = writeln!(out, "{indent}subgraph cluster_s{idx} {{");
+            let _ = writeln!(out, "{indent}    label=\"{name}\";");
+            if self.parallel[idx] {
+                let _ = writeln!(out, "{indent}    style=dashed;");
+            }
+            for child in children {
+                self.dot_state(out, child, depth + 1);
+            }
+            let _ = writeln!(out, "{indent}}}");
+        }
+    }
+
+    fn mermaid_states(&self, out: &mut String, parent: Option<usize>, depth: usize) {
+        let indent = "    ".repeat(depth);
+        if let Some(idx) = self.entry_child(parent) {
+            let _ = writeln!(out, "{indent}[*] --> s{idx}");
+        }
+        for (i, idx) in self.children(parent).into_iter().enumerate() {
+            if i > 0 && self.is_parallel(parent) {
+                let _ = writeln!(out, "{indent}--");
+            }
+            let name = mermaid_escape(self.names[idx]);
+            let _ = writeln!(out, "{indent}state \"{name}\" as s{idx}");
+            if !self.children(Some(idx)).is_empty() {
+                let _ = writeln!(out, "{indent}state s{idx} {{");
+                self.mermaid_states(out, Some(idx), depth + 1);
+                let _ = writeln!(out, "{indent}}}");
+            }
+        }
+    }
+
+    fn plantuml_states(&self, out: &mut String, parent: Option<usize>, depth: usize) {
+        let indent = "  ".repeat(depth);
+        if let Some(idx) = self.entry_child(parent) {
+            let _ = writeln!(out, "{indent}[*] --> s{idx}");
+        }
+        for (i, idx) in self.children(parent).into_iter().enumerate() {
+            if i > 0 && self.is_parallel(parent) {
+                let _ = writeln!(out, "{indent}--");
+            }
+            let name = plantuml_escape(self.names[idx]);
+            let _ = write!(out, "{indent}state \"{name}\" as s{idx}");
+            if self.idx_current == Some(idx) {
+                let _ = write!(out, " {CURRENT_COLOR}");
+            }
+            if self.children(Some(idx)).is_empty() {
+                out.push('\n');
+            } else {
+                out.push_str(" {\n");
+                self.plantuml_states(out, Some(idx), depth + 1);
+                let _ = writeln!(out, "{indent}}}");
+            }
+        }
+    }
+}
+
+// Escape text in a quoted DOT string
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Escape a Mermaid state name or transition label with entity codes,
+// a `:` would start another label and a newline end the statement
+fn mermaid_escape(s: &str) -> String {
+    s.replace('#', "#35;")
+        .replace(':', "#58;")
+        .replace('"', "#quot;")
+        .replace('\n', "<br>")
+}
+
+// Escape a PlantUML state name or transition label, `\n` is PlantUML's newline
+// and the other special characters are written as entities
+fn plantuml_escape(s: &str) -> String {
+    s.replace('&', "&#38;")
+        .replace('\\', "&#92;")
+        .replace(':', "&#58;")
+        .replace('"', "&#34;")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    //         base
+    //        /    \
+    //   parent     other
+    //     |
+    //   initial
+    fn diagram() -> Diagram<'static> {
+        Diagram::new(2)
+            .state("base", None)
+            .state("parent", Some(0))
+            .state("initial", Some(1))
+            .state("other \"2\"", Some(0))
+            .transition(1, 3, "Go")
+            .transition(3, 2, "Back")
+            .current(3)
+    }
+
+    #[test]
+    fn test_to_dot() {
+        assert_eq!(
+            diagram().to_dot(),
+            r##"digraph {
+    compound=true;
+    node [shape=box, style=rounded];
+    subgraph cluster_s0 {
+        label="base";
+        subgraph cluster_s1 {
+            label="parent";
+            s2 [label="initial"];
+        }
+        s3 [label="other \"2\"", style="rounded,filled", fillcolor="#ffcc66"];
+    }
+    initial [shape=point, width=0.15];
+    initial -> s2;
+    s2 -> s3 [label="Go", ltail=cluster_s1];
+    s3 -> s2 [label="Back"];
+}
+"##
+        );
+    }
+
+    #[test]
+    fn test_to_mermaid() {
+        assert_eq!(
+            diagram().to_mermaid(),
+            r##"stateDiagram-v2
+    [*] --> s0
+    state "base" as s0
+    state s0 {
+        [*] --> s1
+        state "parent" as s1
+        state s1 {
+            [*] --> s2
+            state "initial" as s2
+        }
+        state "other #quot;2#quot;" as s3
+    }
+    s1 --> s3 : Go
+    s3 --> s2 : Back
+    classDef current fill:#ffcc66
+    class s3 current
+"##
+        );
+    }
+
+    //       base
+    //        |
+    //     device(||)
+    //     /       \
+    //   conn     power
+    //   /  \     /   \
+    // off  on  off2  on2
+    fn parallel_diagram() -> Diagram<'static> {
+        Diagram::new(3)
+            .state("base", None)
+            .parallel_state("device", Some(0))
+            .state("conn", Some(1))
+            .state("off", Some(2))
+            .state("on", Some(2))
+            .state("power", Some(1))
+            .initial_child(7)
+            .state("off2", Some(5))
+            .state("on2", Some(5))
+    }
+
+    #[test]
+    fn test_parallel() {
+        assert_eq!(
+            parallel_diagram().to_mermaid(),
+            r##"stateDiagram-v2
+    [*] --> s0
+    state "base" as s0
+    state s0 {
+        [*] --> s1
+        state "device" as s1
+        state s1 {
+            state "conn" as s2
+            state s2 {
+                [*] --> s3
+                state "off" as s3
+                state "on" as s4
+            }
+            --
+            state "power" as s5
+            state s5 {
+                [*] --> s7
+                state "off2" as s6
+                state "on2" as s7
+            }
+        }
+    }
+"##
+        );
+        assert!(parallel_diagram()
+            .to_dot()
+            .contains("label=\"device\";\n            style=dashed;\n"));
+        assert!(parallel_diagram()
+            .to_plantuml()
+            .contains("    }\n    --\n    state \"power\" as s5 {\n      [*] --> s7\n"));
+    }
+
+    #[test]
+    fn test_escaped_labels() {
+        let diagram = Diagram::new(0)
+            .state("a", None)
+            .state("b", None)
+            .transition(0, 1, "Go: \"now\"\n#1");
+        assert!(diagram
+            .to_dot()
+            .contains("s0 -> s1 [label=\"Go: \\\"now\\\"\\n#1\"];\n"));
+        assert!(diagram
+            .to_mermaid()
+            .contains("    s0 --> s1 : Go#58; #quot;now#quot;<br>#35;1\n"));
+        assert!(diagram
+            .to_plantuml()
+            .contains("s0 --> s1 : Go&#58; &#34;now&#34;\\n#1\n"));
+    }
+
+    #[test]
+    fn test_escaped_names() {
+        let diagram = Diagram::new(0).state("Go: \"now\" #1", None);
+        assert!(diagram
+            .to_dot()
+            .contains("s0 [label=\"Go: \\\"now\\\" #1\"];\n"));
+        assert!(diagram
+            .to_mermaid()
+            .contains("    state \"Go#58; #quot;now#quot; #35;1\" as s0\n"));
+        assert!(diagram
+            .to_plantuml()
+            .contains("state \"Go&#58; &#34;now&#34; #1\" as s0\n"));
+    }
+
+    #[test]
+    fn test_to_plantuml() {
+        assert_eq!(
+            diagram().to_plantuml(),
+            r##"@startuml
+[*] --> s0
+state "base" as s0 {
+  [*] --> s1
+  state "parent" as s1 {
+    [*] --> s2
+    state "initial" as s2
+  }
+  state "other &#34;2&#34;" as s3 #ffcc66
+}
+s1 --> s3 : Go
+s3 --> s2 : Back
+@enduml
+"##
+        );
+    }
+}
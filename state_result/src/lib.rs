@@ -1,7 +1,24 @@
+pub mod diagram;
+
 pub type StateFnsHdl = usize;
 
 pub enum StateResult {
     NotHandled,
     Handled,
     TransitionTo(StateFnsHdl),
+
+    // Same as Handled if the state is the current state or one of
+    // its parents, nothing is exited or entered, otherwise the
+    // same as TransitionTo
+    InternalTransitionTo(StateFnsHdl),
+}
+
+// A message that no state handled, kept by an hsm1 state machine
+// with a dead_letter_capacity. The message isn't kept as it needn't
+// be Clone or Debug, it's passed to `fn unhandled` if it's defined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    // The state that was current when the message was dispatched
+    pub hdl: StateFnsHdl,
+    pub state: String,
 }